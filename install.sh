@@ -69,6 +69,9 @@ User=$CURRENT_USER
 WorkingDirectory=$WORKING_DIR
 ExecStart=$BINARY_PATH
 Restart=on-success
+KillMode=process
+Delegate=yes
+AmbientCapabilities=CAP_NET_BIND_SERVICE
 RestartSec=0
 StandardOutput=syslog
 StandardError=syslog
@@ -4,6 +4,7 @@ export const user = sqliteTable('servers', {
 	id: integer('id').primaryKey(),
 	name: text('name').notNull(),
 	url: text('url').notNull(),
+	token: text('token'),
 });
 
 export const projects = sqliteTable('projects', {
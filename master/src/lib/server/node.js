@@ -0,0 +1,5 @@
+// Headers for requests from the master to a node, including its API token
+export function nodeHeaders(server, headers = {}) {
+    if (!server.token) return headers;
+    return { ...headers, Authorization: `Bearer ${server.token}` };
+}
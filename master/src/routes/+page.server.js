@@ -3,6 +3,7 @@ import { user } from '$lib/server/db/schema';
 import { fail } from '@sveltejs/kit';
 import { eq } from 'drizzle-orm';
 import { versionCache } from '$lib/server/cache';
+import { nodeHeaders } from '$lib/server/node';
 
 async function getLatestVersion() {
     const cached = versionCache.get('edgezone-node');
@@ -38,13 +39,16 @@ export const load = async () => {
     
     const [serversWithInfo, latestVersion] = await Promise.all([
         Promise.all(
-            servers.map(async (server) => {
+            servers.map(async ({ token, ...server }) => {
+                const hasToken = Boolean(token);
                 try {
-                    const response = await fetch(`${server.url}/info`);
+                    const response = await fetch(`${server.url}/info`, {
+                        headers: nodeHeaders({ token })
+                    });
                     const info = await response.json();
-                    return { ...server, info };
+                    return { ...server, hasToken, info };
                 } catch (error) {
-                    return { ...server, info: null };
+                    return { ...server, hasToken, info: null };
                 }
             })
         ),
@@ -59,13 +63,14 @@ export const actions = {
 		const data = await request.formData();
 		const name = data.get('name');
 		const url = data.get('url');
+		const token = data.get('token') || null;
 
 		if (!name || !url) {
 			return fail(400, { error: 'Name and URL are required' });
 		}
 
 		try {
-			await db.insert(user).values({ name, url });
+			await db.insert(user).values({ name, url, token });
 			return { success: true };
 		} catch (error) {
 			console.error('Failed to add server:', error);
@@ -94,6 +99,8 @@ export const actions = {
 		const id = Number(data.get('id'));
 		const name = data.get('name');
 		const url = data.get('url');
+		// An empty token field keeps the stored one
+		const token = data.get('token');
 
 		if (!id || !name || !url) {
 			return fail(400, { error: 'All fields are required' });
@@ -101,7 +108,7 @@ export const actions = {
 
 		try {
 			await db.update(user)
-				.set({ name, url })
+				.set(token ? { name, url, token } : { name, url })
 				.where(eq(user.id, id));
 			return { success: true };
 		} catch (error) {
@@ -126,7 +133,8 @@ export const actions = {
 			}
 
 			const response = await fetch(`${server.url}/update`, {
-				method: 'POST'
+				method: 'POST',
+				headers: nodeHeaders(server)
 			});
 			console.log(response);
 			if (!response.ok) {
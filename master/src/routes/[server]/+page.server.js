@@ -9,7 +9,8 @@ export const load = async ({ fetch, params }) => {
         throw error(404, 'Server not found');
     }
     
-    const server = servers[0];
+    // The node's API token stays on the master
+    const { token, ...server } = servers[0];
     
     try {
         const projectsRes = await fetch(`/api/servers/${server.id}?path=/projects`);
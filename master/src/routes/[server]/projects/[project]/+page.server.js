@@ -9,7 +9,8 @@ export const load = async ({ fetch, params }) => {
         throw error(404, 'Server not found');
     }
     
-    const server = servers[0];
+    // The node's API token stays on the master
+    const { token, ...server } = servers[0];
     
     try {
         const project = await fetch(`/api/servers/${server.id}?path=/projects/${params.project}`).then(r => r.json());
@@ -39,12 +40,14 @@ export const actions = {
             const project = {
                 name: data.get('name'),
                 git_repo: data.get('git_repo'),
+                git_ref: data.get('git_ref') || null,
                 install_cmd: data.get('install_cmd') || null,
                 build_cmd: data.get('build_cmd') || null,
                 run_cmd: data.get('run_cmd'),
-                env: data.get('env') || null,
+                env: data.get('env') ? JSON.parse(data.get('env')) : null,
                 healthcheck_endpoint: data.get('healthcheck_endpoint') || null,
-                healthcheck_timeout: Number(data.get('healthcheck_timeout')) || 30
+                healthcheck_timeout: Number(data.get('healthcheck_timeout')) || 30000,
+                domain: domain || null
             };
 
             const res = await fetch(`/api/servers/${params.server}?path=/projects/${params.project}`, {
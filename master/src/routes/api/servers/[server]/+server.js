@@ -2,6 +2,7 @@ import { db } from '$lib/server/db';
 import { user } from '$lib/server/db/schema';
 import { eq } from 'drizzle-orm';
 import { error, json } from '@sveltejs/kit';
+import { nodeHeaders } from '$lib/server/node';
 
 async function getServer(serverId) {
     const servers = await db.select().from(user).where(eq(user.id, Number(serverId)));
@@ -17,7 +18,9 @@ export async function GET({ params, url }) {
 
     
     try {
-        const response = await fetch(`${server.url}${path}`);
+        const response = await fetch(`${server.url}${path}`, {
+            headers: nodeHeaders(server)
+        });
         const data = await response.json();
         return json(data);
     } catch (e) {
@@ -33,10 +36,10 @@ export async function POST({ params, url, request }) {
         const body = await request.json().catch(() => ({}));
         const response = await fetch(`${server.url}${path}`, {
             method: 'POST',
-            headers: {
+            headers: nodeHeaders(server, {
                 'Content-Type': 'application/json',
                 'Accept': 'application/json'
-            },
+            }),
             body: JSON.stringify(body)
         });
 
@@ -77,9 +80,9 @@ export async function PUT({ params, url, request }) {
         const body = await request.json();
         const response = await fetch(`${server.url}${path}`, {
             method: 'PUT',
-            headers: {
+            headers: nodeHeaders(server, {
                 'Content-Type': 'application/json'
-            },
+            }),
             body: JSON.stringify(body)
         });
         const data = await response.json();
@@ -95,7 +98,8 @@ export async function DELETE({ params, url }) {
     
     try {
         const response = await fetch(`${server.url}${path}`, {
-            method: 'DELETE'
+            method: 'DELETE',
+            headers: nodeHeaders(server)
         });
         const data = await response.json();
         return json(data);
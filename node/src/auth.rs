@@ -0,0 +1,99 @@
+use axum::{
+    extract::{Request, State},
+    http::header,
+    middleware::Next,
+    response::Response,
+};
+use rand::RngCore;
+use std::{fs, io::Write, os::unix::fs::OpenOptionsExt, sync::Arc};
+
+use crate::error::AppError;
+
+const TOKEN_FILE: &str = "./api_token";
+
+/// Returns the shared secret the master has to present. A token given on the
+/// command line wins, otherwise the one stored next to data.db is used and
+/// generated on first start.
+pub fn load_or_create_token(cli_token: Option<String>) -> Arc<str> {
+    if let Some(token) = cli_token.filter(|t| !t.trim().is_empty()) {
+        return Arc::from(token.trim());
+    }
+
+    if let Ok(token) = fs::read_to_string(TOKEN_FILE) {
+        if !token.trim().is_empty() {
+            return Arc::from(token.trim());
+        }
+    }
+
+    let mut bytes = [0u8; 32];
+    rand::rng().fill_bytes(&mut bytes);
+    let token: String = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+
+    let mut file = fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(TOKEN_FILE)
+        .unwrap();
+    file.write_all(token.as_bytes()).unwrap();
+
+    println!("Generated API token, add it to this node in the master console: {}", token);
+    Arc::from(token)
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+pub async fn require_token(
+    State(token): State<Arc<str>>,
+    request: Request,
+    next: Next,
+) -> Result<Response, AppError> {
+    let presented = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(str::trim);
+
+    match presented {
+        Some(presented) if constant_time_eq(presented.as_bytes(), token.as_bytes()) => {
+            Ok(next.run(request).await)
+        }
+        _ => Err(AppError::Unauthorized),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{middleware, routing::get, Router};
+
+    #[tokio::test]
+    async fn requests_need_the_token() {
+        let app = Router::new()
+            .route("/projects", get(|| async { "ok" }))
+            .route_layer(middleware::from_fn_with_state(Arc::<str>::from("s3cret"), require_token));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/projects", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        let client = reqwest::Client::new();
+
+        let response = client.get(&url).send().await.unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::UNAUTHORIZED);
+        assert_eq!(response.headers()[header::WWW_AUTHENTICATE], "Bearer");
+        for wrong in ["Bearer s3cre", "Bearer s3cret2", "Basic s3cret", "s3cret"] {
+            let response = client.get(&url).header(header::AUTHORIZATION, wrong).send().await.unwrap();
+            assert_eq!(response.status(), reqwest::StatusCode::UNAUTHORIZED, "{}", wrong);
+        }
+
+        let response = client.get(&url).bearer_auth("s3cret").send().await.unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::OK);
+        assert_eq!(response.text().await.unwrap(), "ok");
+    }
+}
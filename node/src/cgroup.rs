@@ -0,0 +1,167 @@
+use std::{
+    ffi::CString,
+    fs,
+    os::unix::ffi::OsStrExt,
+    path::{Path, PathBuf},
+    sync::OnceLock,
+};
+use nix::{fcntl::{open, OFlag}, sys::stat::Mode, unistd::write};
+
+const CONTROLLERS: [&str; 3] = ["memory", "cpu", "pids"];
+/// cpu.max quotas are given per this many microseconds.
+const CPU_PERIOD: u64 = 100_000;
+/// The cgroup below the node's own that deployments get their cgroups in,
+/// None when cgroup v2 can't be used here.
+static DEPLOYMENTS: OnceLock<Option<PathBuf>> = OnceLock::new();
+
+/// Resource limits of a project, None meaning unlimited.
+#[derive(Clone, Copy)]
+pub struct Limits {
+    /// In MiB.
+    pub memory: Option<i32>,
+    /// In percent of one CPU.
+    pub cpu: Option<i32>,
+    pub pids: Option<i32>,
+}
+
+impl Limits {
+    pub fn is_empty(&self) -> bool {
+        self.memory.is_none() && self.cpu.is_none() && self.pids.is_none()
+    }
+}
+
+/// Prepares the node's cgroup for handing out limits, once at startup. Under
+/// systemd that takes `Delegate=yes` in the unit.
+pub fn init() {
+    let deployments = match setup() {
+        Ok(deployments) => Some(deployments),
+        Err(e) => {
+            eprintln!("cgroup v2 is not available, project resource limits won't be applied: {}", e);
+            None
+        }
+    };
+    let _ = DEPLOYMENTS.set(deployments);
+}
+
+fn setup() -> std::io::Result<PathBuf> {
+    let mountinfo = fs::read_to_string("/proc/self/mountinfo")?;
+    // The filesystem type is the first field after the " - " separator
+    let mount = mountinfo
+        .lines()
+        .find(|line| line.split(" - ").nth(1).is_some_and(|fs| fs.starts_with("cgroup2 ")))
+        .and_then(|line| line.split(' ').nth(4))
+        .ok_or_else(|| std::io::Error::other("no cgroup2 filesystem is mounted"))?;
+    let own = fs::read_to_string("/proc/self/cgroup")?
+        .lines()
+        .find_map(|line| line.strip_prefix("0::").map(str::to_string))
+        .ok_or_else(|| std::io::Error::other("the node is not in a cgroup v2 hierarchy"))?;
+    // Everything on the machine would be moved along with it
+    if own.trim() == "/" {
+        return Err(std::io::Error::other("the node runs in the root cgroup rather than one of its own"));
+    }
+    let mut base = Path::new(mount).join(own.trim_start_matches('/'));
+    // After a restart in place the node already sits in the leaf it moved itself to
+    if base.ends_with("node") {
+        base.pop();
+    }
+
+    let available = fs::read_to_string(base.join("cgroup.controllers"))?;
+    let enable: Vec<String> = CONTROLLERS
+        .iter()
+        .filter(|c| available.split_whitespace().any(|a| a == **c))
+        .map(|c| format!("+{}", c))
+        .collect();
+    if enable.is_empty() {
+        return Err(std::io::Error::other("none of the memory, cpu and pids controllers are available"));
+    }
+
+    // Only leaves can hold processes once controllers are handed down, so the
+    // node and whatever else lives in its cgroup move into one of their own
+    let node = base.join("node");
+    fs::create_dir_all(&node)?;
+    for pid in fs::read_to_string(base.join("cgroup.procs"))?.lines() {
+        let _ = fs::write(node.join("cgroup.procs"), pid);
+    }
+    let deployments = base.join("deployments");
+    fs::create_dir_all(&deployments)?;
+    fs::write(base.join("cgroup.subtree_control"), enable.join(" "))?;
+    fs::write(deployments.join("cgroup.subtree_control"), enable.join(" "))?;
+    Ok(deployments)
+}
+
+/// The cgroup a deployment's install, build and run commands share.
+#[derive(Clone)]
+pub struct Cgroup {
+    path: PathBuf,
+}
+
+impl Cgroup {
+    /// Creates the cgroup of a deployment, or updates it when it is already
+    /// there, with `limits`. None when there is nothing to limit.
+    pub fn create(deployment_id: i64, limits: &Limits) -> std::io::Result<Option<Self>> {
+        if limits.is_empty() {
+            return Ok(None);
+        }
+        let deployments = DEPLOYMENTS
+            .get()
+            .cloned()
+            .flatten()
+            .ok_or_else(|| std::io::Error::other("cgroup v2 is not available"))?;
+        let path = deployments.join(format!("deployment-{}", deployment_id));
+        fs::create_dir_all(&path)?;
+
+        let memory = limits.memory.map(|mb| (mb as u64 * 1024 * 1024).to_string());
+        let cpu = limits.cpu.map(|percent| format!("{} {}", percent as u64 * CPU_PERIOD / 100, CPU_PERIOD));
+        let pids = limits.pids.map(|n| n.to_string());
+        set(&path.join("memory.max"), memory, "max")?;
+        set(&path.join("cpu.max"), cpu, &format!("max {}", CPU_PERIOD))?;
+        set(&path.join("pids.max"), pids, "max")?;
+        Ok(Some(Cgroup { path }))
+    }
+
+    /// Has `command` move itself into the cgroup before it is executed, so
+    /// that nothing it starts can end up outside of it.
+    pub fn apply(&self, command: &mut tokio::process::Command) -> std::io::Result<()> {
+        let procs = CString::new(self.path.join("cgroup.procs").as_os_str().as_bytes()).map_err(std::io::Error::other)?;
+        let join = move || -> std::io::Result<()> {
+            // Writing 0 moves the writing process
+            let fd = open(procs.as_c_str(), OFlag::O_WRONLY | OFlag::O_CLOEXEC, Mode::empty())?;
+            write(&fd, b"0")?;
+            Ok(())
+        };
+        // SAFETY: the closure only makes system calls on memory prepared before the fork
+        unsafe {
+            command.pre_exec(join);
+        }
+        Ok(())
+    }
+
+    /// How many processes the kernel OOM killed in this cgroup so far.
+    pub fn oom_kills(&self) -> u64 {
+        fs::read_to_string(self.path.join("memory.events"))
+            .ok()
+            .and_then(|events| {
+                events
+                    .lines()
+                    .find_map(|line| line.strip_prefix("oom_kill ")?.trim().parse().ok())
+            })
+            .unwrap_or(0)
+    }
+}
+
+/// Writes `value` to a limit file, or `unlimited` to lift a limit set earlier.
+/// Only setting a limit needs its controller to be enabled.
+fn set(file: &Path, value: Option<String>, unlimited: &str) -> std::io::Result<()> {
+    match value {
+        Some(value) => fs::write(file, value),
+        None if file.exists() => fs::write(file, unlimited),
+        None => Ok(()),
+    }
+}
+
+/// Removes a deployment's cgroup once nothing runs in it anymore.
+pub fn remove(deployment_id: i64) {
+    if let Some(Some(deployments)) = DEPLOYMENTS.get() {
+        let _ = fs::remove_dir(deployments.join(format!("deployment-{}", deployment_id)));
+    }
+}
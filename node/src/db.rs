@@ -1,18 +1,32 @@
 use libsql::Builder;
-use std::sync::Arc;
+use std::{fs, os::unix::fs::PermissionsExt, sync::Arc};
+
+use crate::logs::LogHub;
+use crate::metrics::Metrics;
+use crate::proxy::Routes;
+use crate::update::Updater;
 
 #[derive(Clone)]
 pub struct AppState {
     pub db: Arc<libsql::Database>,
+    pub logs: LogHub,
+    pub updates: Updater,
+    pub metrics: Metrics,
+    pub routes: Routes,
 }
 
-pub async fn init_db() -> AppState {
+pub async fn init_db(updates: Updater) -> AppState {
     let db = Builder::new_local("./data.db").build().await.unwrap();
     let conn = db.connect().unwrap();
     conn.query("PRAGMA journal_mode = WAL", ()).await.unwrap();
     conn.query("PRAGMA busy_timeout = 5000", ()).await.unwrap();
     create_tables(&db).await;
-    AppState { db: Arc::new(db) }
+    migrate_tables(&db).await;
+    // Isolated projects have no business reading the database
+    for file in ["./data.db", "./data.db-wal", "./data.db-shm"] {
+        let _ = fs::set_permissions(file, fs::Permissions::from_mode(0o600));
+    }
+    AppState { db: Arc::new(db), logs: LogHub::default(), updates, metrics: Metrics::default(), routes: Routes::default() }
 }
 
 async fn create_tables(db: &libsql::Database) {
@@ -51,3 +65,149 @@ async fn create_tables(db: &libsql::Database) {
     .await
     .unwrap();
 }
+
+/// Adds a column to a table created by an older version of the node.
+async fn add_column(conn: &libsql::Connection, table: &str, column: &str, definition: &str) {
+    let mut rows = conn
+        .query(&format!("PRAGMA table_info({})", table), ())
+        .await
+        .unwrap();
+    while let Some(row) = rows.next().await.unwrap() {
+        let name: String = row.get(1).unwrap();
+        if name == column {
+            return;
+        }
+    }
+    conn.execute(&format!("ALTER TABLE {} ADD COLUMN {} {}", table, column, definition), ())
+        .await
+        .unwrap();
+}
+
+async fn migrate_tables(db: &libsql::Database) {
+    let conn = db.connect().unwrap();
+
+    add_column(&conn, "projects", "restart_policy", "TEXT DEFAULT 'never'").await;
+    add_column(&conn, "projects", "max_restarts", "INTEGER DEFAULT 5").await;
+    add_column(&conn, "projects", "stop_timeout", "INTEGER DEFAULT 10000").await;
+    add_column(&conn, "deployments", "restart_count", "INTEGER DEFAULT 0").await;
+    add_column(&conn, "projects", "git_ref", "TEXT").await;
+    add_column(&conn, "deployments", "git_ref", "TEXT").await;
+    add_column(&conn, "deployments", "build_id", "INTEGER").await;
+    add_column(&conn, "projects", "clone_depth", "INTEGER").await;
+    add_column(&conn, "projects", "submodules", "INTEGER DEFAULT 0").await;
+    add_column(&conn, "projects", "keep_deployments", "INTEGER DEFAULT 5").await;
+    add_column(&conn, "deployments", "pinned", "INTEGER DEFAULT 0").await;
+    add_column(&conn, "projects", "stopped", "INTEGER DEFAULT 0").await;
+    add_column(&conn, "projects", "isolation", "TEXT DEFAULT 'none'").await;
+    add_column(&conn, "projects", "memory_limit", "INTEGER").await;
+    add_column(&conn, "projects", "cpu_limit", "INTEGER").await;
+    add_column(&conn, "projects", "pids_limit", "INTEGER").await;
+    add_column(&conn, "deployments", "oom_kills", "INTEGER DEFAULT 0").await;
+    add_column(&conn, "projects", "domain", "TEXT").await;
+    add_column(&conn, "projects", "port", "INTEGER").await;
+    add_column(&conn, "deployments", "port", "INTEGER").await;
+    // The order deployments were last started in, which restarting one changes
+    add_column(&conn, "deployments", "sequence", "INTEGER").await;
+    conn.execute("UPDATE deployments SET sequence = id WHERE sequence IS NULL", ())
+        .await
+        .unwrap();
+
+    // Data migrations run once each, user_version counts the ones that did
+    let version: i64 = {
+        let mut rows = conn.query("PRAGMA user_version", ()).await.unwrap();
+        rows.next().await.unwrap().map_or(0, |row| row.get(0).unwrap())
+    };
+    if version < 1 {
+        // The console used to send the healthcheck timeout in seconds, always 30
+        conn.execute("UPDATE projects SET healthcheck_timeout = 30000 WHERE healthcheck_timeout = 30", ())
+            .await
+            .unwrap();
+        conn.execute("PRAGMA user_version = 1", ()).await.unwrap();
+    }
+
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS deployment_restarts (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        deployment_id INTEGER NOT NULL,
+        exit_code INTEGER,
+        signal INTEGER,
+        created_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+        FOREIGN KEY(deployment_id) REFERENCES deployments(id)
+    )",
+        (),
+    )
+    .await
+    .unwrap();
+
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS webhooks (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        url TEXT NOT NULL,
+        project_id INTEGER,
+        events TEXT,
+        secret TEXT NOT NULL,
+        created_at DATETIME DEFAULT CURRENT_TIMESTAMP
+    )",
+        (),
+    )
+    .await
+    .unwrap();
+
+    // The outbox events wait in until their webhook accepted them
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS webhook_deliveries (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        webhook_id INTEGER NOT NULL,
+        event_type TEXT NOT NULL,
+        payload TEXT NOT NULL,
+        attempts INTEGER DEFAULT 0,
+        last_error TEXT,
+        next_attempt INTEGER,
+        delivered_at INTEGER,
+        failed_at INTEGER,
+        created_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+        FOREIGN KEY(webhook_id) REFERENCES webhooks(id)
+    )",
+        (),
+    )
+    .await
+    .unwrap();
+
+    // Values are encrypted with the node's secret key, see secrets.rs
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS secrets (
+        project_id INTEGER NOT NULL,
+        key TEXT NOT NULL,
+        value BLOB NOT NULL,
+        updated_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+        PRIMARY KEY(project_id, key),
+        FOREIGN KEY(project_id) REFERENCES projects(id)
+    )",
+        (),
+    )
+    .await
+    .unwrap();
+}
+
+/// A database of its own in the temp directory with every table in place,
+/// for tests that go through the database.
+#[cfg(test)]
+pub async fn test_state(name: &str) -> AppState {
+    let file = std::env::temp_dir().join(format!("edgezone-{}-{}.db", name, std::process::id()));
+    for suffix in ["", "-wal", "-shm"] {
+        let _ = fs::remove_file(format!("{}{}", file.display(), suffix));
+    }
+    let db = Builder::new_local(&file).build().await.unwrap();
+    let conn = db.connect().unwrap();
+    conn.query("PRAGMA journal_mode = WAL", ()).await.unwrap();
+    conn.query("PRAGMA busy_timeout = 5000", ()).await.unwrap();
+    create_tables(&db).await;
+    migrate_tables(&db).await;
+    AppState {
+        db: Arc::new(db),
+        logs: LogHub::default(),
+        updates: Updater::new(None, None),
+        metrics: Metrics::default(),
+        routes: Routes::default(),
+    }
+}
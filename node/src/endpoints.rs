@@ -3,14 +3,29 @@ use crate::error::AppError;
 use serde_derive::{Deserialize, Serialize};
 
 use axum::{
-    extract::{Path, State},
+    body::Bytes,
+    extract::{ws::{Message, WebSocketUpgrade}, Path, Query, State},
     http::{StatusCode, HeaderMap, header},
+    response::{sse::{Event, KeepAlive, Sse}, IntoResponse, Response},
     Json,
 };
+use std::{convert::Infallible, path::PathBuf};
+use tokio_stream::{wrappers::ReceiverStream, Stream, StreamExt};
+
+use crate::env_vars::{self, EnvVar, EnvVars};
+use crate::events::{self, Delivery, Webhook};
+use crate::logs::{self, LogFilter, LogPage};
+use crate::metrics::{NodeSample, ProcessSample};
+use crate::proxy;
+use crate::sandbox::Isolation;
+use crate::secrets::{self, Secret};
+use crate::update::UpdateStatus;
 
 mod core;
 
-#[derive(Serialize, Deserialize)]
+pub use core::{fail_interrupted, prune_deployments, resume_deployment, running_pgid};
+
+#[derive(Clone, Serialize, Deserialize)]
 pub struct Project {
     pub id: Option<i32>,
     pub name: String,
@@ -18,18 +33,149 @@ pub struct Project {
     pub install_cmd: Option<String>,
     pub build_cmd: Option<String>,
     pub run_cmd: Option<String>,
-    pub env: Option<String>,
+    /// Plain configuration, readable through the API and written to `.env`.
+    /// Also accepted as dotenv text.
+    #[serde(default, deserialize_with = "env_vars::deserialize")]
+    pub env: Option<EnvVars>,
     pub healthcheck_endpoint: Option<String>,
     pub healthcheck_timeout: Option<i32>,
+    pub restart_policy: Option<String>,
+    pub max_restarts: Option<i32>,
+    pub stop_timeout: Option<i32>,
+    pub git_ref: Option<String>,
+    /// Fetch only this many commits of history, everything when unset.
+    pub clone_depth: Option<i32>,
+    pub submodules: Option<bool>,
+    /// How many of the latest deployments the janitor leaves on disk.
+    pub keep_deployments: Option<i32>,
+    /// Set by the stop endpoint and cleared by starting or deploying again.
+    /// Ignored when creating or updating a project.
+    pub stopped: Option<bool>,
+    /// `none`, `user` for a uid/gid of its own, or `namespace` to also make
+    /// everything but its working directory read-only.
+    pub isolation: Option<String>,
+    /// Memory limit in MiB, applied through cgroup v2 like the two below.
+    pub memory_limit: Option<i32>,
+    /// CPU limit in percent of one CPU, 200 being two full CPUs.
+    pub cpu_limit: Option<i32>,
+    /// How many processes and threads the deployment can have at once.
+    pub pids_limit: Option<i32>,
+    /// Host name the node's proxy routes to this project, when it runs one.
+    pub domain: Option<String>,
+    /// Fixed port the run command listens on, for apps that ignore PORT. The
+    /// proxy sends requests here instead of to the deployment's own port.
+    pub port: Option<i32>,
+}
+
+impl Project {
+    /// Column list matching `from_row`, for SELECTs on the projects table.
+    pub const COLUMNS: &'static str = "id, name, git_repo, install_cmd, build_cmd, run_cmd, env, healthcheck_endpoint, healthcheck_timeout, restart_policy, max_restarts, stop_timeout, git_ref, clone_depth, submodules, keep_deployments, stopped, isolation, memory_limit, cpu_limit, pids_limit, domain, port";
+
+    pub fn from_row(row: &libsql::Row) -> Result<Self, AppError> {
+        Ok(Project {
+            id: row.get(0)?,
+            name: row.get(1)?,
+            git_repo: row.get(2)?,
+            install_cmd: row.get(3)?,
+            build_cmd: row.get(4)?,
+            run_cmd: row.get(5)?,
+            env: env_vars::from_column(row.get(6)?),
+            healthcheck_endpoint: row.get(7)?,
+            healthcheck_timeout: row.get(8)?,
+            restart_policy: row.get(9)?,
+            max_restarts: row.get(10)?,
+            stop_timeout: row.get(11)?,
+            git_ref: row.get(12)?,
+            clone_depth: row.get(13)?,
+            submodules: row.get(14)?,
+            keep_deployments: row.get(15)?,
+            stopped: row.get(16)?,
+            isolation: row.get(17)?,
+            memory_limit: row.get(18)?,
+            cpu_limit: row.get(19)?,
+            pids_limit: row.get(20)?,
+            domain: row.get(21)?,
+            port: row.get(22)?,
+        })
+    }
+
+    fn validate(&self) -> Result<(), AppError> {
+        if !core::valid_project_name(&self.name) {
+            return Err(AppError::BadRequest);
+        }
+        core::RestartPolicy::parse(self.restart_policy.as_deref())?;
+        if let Some(env) = &self.env {
+            env_vars::validate(env)?;
+        }
+        Isolation::parse(self.isolation.as_deref())?;
+        if self.max_restarts.is_some_and(|n| n < 0)
+            || self.stop_timeout.is_some_and(|n| n < 0)
+            || self.clone_depth.is_some_and(|n| n < 0)
+            || self.keep_deployments.is_some_and(|n| n < 0)
+            || self.memory_limit.is_some_and(|n| n <= 0)
+            || self.cpu_limit.is_some_and(|n| n <= 0)
+            || self.pids_limit.is_some_and(|n| n <= 0)
+            || self.port.is_some_and(|n| !(1..=65535).contains(&n))
+        {
+            return Err(AppError::BadRequest);
+        }
+        if self.domain.as_deref().is_some_and(|d| !proxy::valid_domain(d)) {
+            return Err(AppError::BadRequest);
+        }
+        if self.git_ref.as_deref().is_some_and(|r| !core::valid_git_ref(r)) {
+            return Err(AppError::BadRequest);
+        }
+        Ok(())
+    }
+
+    /// What it takes to bring the running deployment in line with this
+    /// project, when it was deployed as `old`.
+    fn change_from(&self, old: &Project) -> Change {
+        let env = |project: &Project| project.env.clone().unwrap_or_default();
+        if self.name != old.name
+            || self.git_repo != old.git_repo
+            || self.git_ref != old.git_ref
+            || self.install_cmd != old.install_cmd
+            || self.build_cmd != old.build_cmd
+            || self.clone_depth != old.clone_depth
+            || self.submodules != old.submodules
+            || self.isolation != old.isolation
+        {
+            Change::Build
+        } else if env(self) != env(old) || self.run_cmd != old.run_cmd {
+            Change::Runtime
+        } else {
+            Change::None
+        }
+    }
+}
+
+/// How a project update reaches its running deployment. Anything else it
+/// changes applies from the next deployment or restart on.
+#[derive(PartialEq)]
+enum Change {
+    None,
+    /// The environment or run command, picked up by restarting on the same build.
+    Runtime,
+    /// The repository or how it is built, which takes a new deploy.
+    Build,
 }
 
 #[derive(Serialize, Deserialize)]
 pub struct Deployment {
     pub id: Option<i32>,
     pub project_id: i32,
+    pub git_ref: Option<String>,
+    pub build_id: Option<i64>,
     pub commit_hash: String,
     pub status: i32,
+    pub pinned: bool,
     pub logs: String,
+    pub restart_count: i32,
+    /// Processes the kernel killed for going over the memory limit.
+    pub oom_kills: i64,
+    /// Port the node gave the deployment, passed to its commands as PORT.
+    pub port: Option<i32>,
     pub created_at: String,
 }
 
@@ -43,11 +189,101 @@ pub struct MiniProj {
 pub struct MiniDep {
     pub id: i32,
     pub project_id: i32,
+    pub git_ref: Option<String>,
+    pub build_id: Option<i64>,
     pub commit_hash: String,
     pub status: i32,
+    pub pinned: bool,
     pub created_at: String,
 }
 
+/// Optional body of a deploy request. `git_ref` overrides the project's ref
+/// for this deployment only.
+#[derive(Deserialize)]
+pub struct DeployRequest {
+    pub git_ref: Option<String>,
+}
+
+/// Optional body of an update request.
+#[derive(Deserialize)]
+pub struct UpdateRequest {
+    pub force: Option<bool>,
+}
+
+/// What a prune removed: the deployments and the bytes their directories took.
+#[derive(Serialize)]
+pub struct PruneReport {
+    pub deployments: Vec<i64>,
+    pub bytes: u64,
+}
+
+/// `files` is `keep` (the default), `archive` or `remove`.
+#[derive(Deserialize)]
+pub struct DeleteQuery {
+    pub files: Option<String>,
+}
+
+/// Only samples taken after `since`, a unix timestamp, when given.
+#[derive(Deserialize)]
+pub struct MetricsQuery {
+    pub since: Option<i64>,
+}
+
+#[derive(Deserialize)]
+pub struct DeliveriesQuery {
+    pub limit: Option<i64>,
+}
+
+#[derive(Deserialize)]
+pub struct ImportEnvQuery {
+    pub replace: Option<bool>,
+}
+
+/// What deleting a project cleaned up. `files` says whether its directory was
+/// kept, archived or removed, and `bytes` how much space that directory took.
+#[derive(Serialize)]
+pub struct DeleteReport {
+    pub id: i32,
+    pub name: String,
+    pub stopped: Vec<i64>,
+    pub deployments: u64,
+    pub files: String,
+    pub archive: Option<String>,
+    pub bytes: u64,
+}
+
+/// The deployments a stop request took down.
+#[derive(Serialize)]
+pub struct StopReport {
+    pub deployments: Vec<i64>,
+}
+
+#[derive(Serialize)]
+pub struct Restart {
+    pub id: i32,
+    pub deployment_id: i32,
+    pub exit_code: Option<i32>,
+    pub signal: Option<i32>,
+    pub created_at: String,
+}
+
+#[derive(Deserialize)]
+pub struct LogQuery {
+    pub since: Option<u64>,
+}
+
+/// Either a byte range (`offset`, `length`) or a line range (`line`, `lines`)
+/// of a deployment's log. A negative `line` counts from the end. `format` is
+/// `json` (the default) or `jsonl` for one record per line.
+#[derive(Deserialize)]
+pub struct LogRangeQuery {
+    pub offset: Option<u64>,
+    pub length: Option<u64>,
+    pub line: Option<i64>,
+    pub lines: Option<usize>,
+    pub format: Option<String>,
+}
+
 #[derive(Serialize)]
 pub struct Info {
     name: String,
@@ -65,24 +301,49 @@ pub const STATUS_BUILDING: i32 = 2;
 pub const STATUS_RUNNING: i32 = 3;
 pub const STATUS_FAILED: i32 = 4;
 pub const STATUS_STOPPED: i32 = 5;
+pub const STATUS_STARTING: i32 = 6;
+pub const STATUS_CRASHLOOP: i32 = 7;
+
+/// How much of a log is returned when no range is asked for.
+const DEFAULT_LOG_LENGTH: u64 = 64 * 1024;
+const DEFAULT_LOG_LINES: usize = 100;
+const DEFAULT_DELIVERIES: i64 = 50;
+const MAX_DELIVERIES: i64 = 500;
 
 pub async fn create_project(
     State(state): State<AppState>,
     Json(project): Json<Project>,
 ) -> Result<(StatusCode, Json<Project>), AppError> {
+    let project = Project { domain: normalize_domain(project.domain), ..project };
+    project.validate()?;
     let conn = state.db.connect()?;
+    check_domain_free(&conn, project.domain.as_deref(), None).await?;
 
     conn.execute(
-        "INSERT INTO projects (name, git_repo, install_cmd, build_cmd, run_cmd, healthcheck_endpoint, healthcheck_timeout) VALUES (?, ?, ?, ?, ?, ?, ?)",
-        (
+        "INSERT INTO projects (name, git_repo, install_cmd, build_cmd, run_cmd, env, healthcheck_endpoint, healthcheck_timeout, restart_policy, max_restarts, stop_timeout, git_ref, clone_depth, submodules, keep_deployments, isolation, memory_limit, cpu_limit, pids_limit, domain, port) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
+        libsql::params![
             project.name.clone(),
             project.git_repo.clone(),
             project.install_cmd.clone(),
             project.build_cmd.clone(),
             project.run_cmd.clone(),
+            env_vars::to_column(project.env.as_ref()),
             project.healthcheck_endpoint.clone(),
             project.healthcheck_timeout,
-        ),
+            project.restart_policy.clone(),
+            project.max_restarts,
+            project.stop_timeout,
+            project.git_ref.clone(),
+            project.clone_depth,
+            project.submodules,
+            project.keep_deployments,
+            project.isolation.clone(),
+            project.memory_limit,
+            project.cpu_limit,
+            project.pids_limit,
+            project.domain.clone(),
+            project.port,
+        ],
     )
     .await?;
     
@@ -90,6 +351,28 @@ pub async fn create_project(
     Ok((StatusCode::CREATED, Json(project)))
 }
 
+/// Domains are matched case-insensitively, and an empty one means none.
+fn normalize_domain(domain: Option<String>) -> Option<String> {
+    domain
+        .map(|d| d.trim().to_ascii_lowercase())
+        .filter(|d| !d.is_empty())
+}
+
+/// Two projects can't share a domain, the proxy wouldn't know which one to pick.
+async fn check_domain_free(conn: &libsql::Connection, domain: Option<&str>, except: Option<&str>) -> Result<(), AppError> {
+    let Some(domain) = domain else { return Ok(()) };
+    let mut rows = conn
+        .query(
+            "SELECT id FROM projects WHERE domain = ? AND id != ?",
+            (domain, except.unwrap_or("-1")),
+        )
+        .await?;
+    if rows.next().await?.is_some() {
+        return Err(AppError::BadRequest);
+    }
+    Ok(())
+}
+
 pub async fn list_projects(
     State(state): State<AppState>,
 ) -> Result<Json<Vec<MiniProj>>, AppError> {
@@ -116,7 +399,7 @@ pub async fn get_project(
 
     let mut rows = conn
         .query(
-            "SELECT id, name, git_repo, install_cmd, build_cmd, run_cmd, env, healthcheck_endpoint, healthcheck_timeout FROM projects WHERE id = ?",
+            &format!("SELECT {} FROM projects WHERE id = ?", Project::COLUMNS),
             [id],
         )
         .await?;
@@ -126,41 +409,68 @@ pub async fn get_project(
         .await?
         .ok_or(AppError::NotFound)?;
 
-    Ok(Json(Project {
-        id: row.get(0)?,
-        name: row.get(1)?,
-        git_repo: row.get(2)?,
-        install_cmd: row.get(3)?,
-        build_cmd: row.get(4)?,
-        run_cmd: row.get(5)?,
-        env: row.get(6)?,
-        healthcheck_endpoint: row.get(7)?,
-        healthcheck_timeout: row.get(8)?,
-    }))
+    Ok(Json(Project::from_row(&row)?))
 }
 
 pub async fn update_project(
     State(state): State<AppState>,
     Path(id): Path<String>,
-    Json(project): Json<Project>,
+    Json(fields): Json<serde_json::Map<String, serde_json::Value>>,
 ) -> Result<Json<Project>, AppError> {
     let conn = state.db.connect()?;
+    let old = {
+        let mut rows = conn
+            .query(&format!("SELECT {} FROM projects WHERE id = ?", Project::COLUMNS), [id.clone()])
+            .await?;
+        let row = rows.next().await?.ok_or(AppError::NotFound)?;
+        Project::from_row(&row)?
+    };
+    // Fields left out keep their current value, a null clears them
+    let mut merged = serde_json::to_value(&old).map_err(|e| AppError::Internal(e.to_string()))?;
+    if let Some(merged) = merged.as_object_mut() {
+        merged.extend(fields);
+    }
+    let project: Project = serde_json::from_value(merged).map_err(|_| AppError::BadRequest)?;
+    let project = Project { domain: normalize_domain(project.domain), ..project };
+    project.validate()?;
+    check_domain_free(&conn, project.domain.as_deref(), Some(&id)).await?;
 
     conn.execute(
-        "UPDATE projects SET name = ?, git_repo = ?, install_cmd = ?, build_cmd = ?, run_cmd = ?, env = ?, healthcheck_endpoint = ?, healthcheck_timeout = ? WHERE id = ?",
-        (
+        "UPDATE projects SET name = ?, git_repo = ?, install_cmd = ?, build_cmd = ?, run_cmd = ?, env = ?, healthcheck_endpoint = ?, healthcheck_timeout = ?, restart_policy = ?, max_restarts = ?, stop_timeout = ?, git_ref = ?, clone_depth = ?, submodules = ?, keep_deployments = ?, isolation = ?, memory_limit = ?, cpu_limit = ?, pids_limit = ?, domain = ?, port = ? WHERE id = ?",
+        libsql::params![
             project.name.clone(),
             project.git_repo.clone(),
             project.install_cmd.clone(),
             project.build_cmd.clone(),
             project.run_cmd.clone(),
-            project.env.clone(),
+            env_vars::to_column(project.env.as_ref()),
             project.healthcheck_endpoint.clone(),
             project.healthcheck_timeout,
-            id,
-        ),
+            project.restart_policy.clone(),
+            project.max_restarts,
+            project.stop_timeout,
+            project.git_ref.clone(),
+            project.clone_depth,
+            project.submodules,
+            project.keep_deployments,
+            project.isolation.clone(),
+            project.memory_limit,
+            project.cpu_limit,
+            project.pids_limit,
+            project.domain.clone(),
+            project.port,
+            id.clone(),
+        ],
     )
     .await?;
+    state.routes.refresh(&conn).await;
+    match project.change_from(&old) {
+        Change::Runtime => {
+            restart_running(&state, id.parse()?, "The environment or run command changed").await?;
+        }
+        Change::Build => println!("Project {} changed how it is built, the change applies from its next deploy", project.name),
+        Change::None => {}
+    }
 
     Ok(Json(project))
 }
@@ -168,24 +478,87 @@ pub async fn update_project(
 pub async fn deploy(
     State(state): State<AppState>,
     Path(project_id): Path<String>,
+    body: Bytes,
 ) -> Result<(StatusCode, HeaderMap, Json<Deployment>), AppError> {
+    // The body is optional, an empty one deploys the project's own ref
+    let request: DeployRequest = if body.is_empty() {
+        DeployRequest { git_ref: None }
+    } else {
+        serde_json::from_slice(&body).map_err(|_| AppError::BadRequest)?
+    };
+    // Without an override the project's ref is filled in once the checkout resolved it
+    let git_ref = request.git_ref.filter(|r| !r.is_empty());
+    if git_ref.as_deref().is_some_and(|r| !core::valid_git_ref(r)) {
+        return Err(AppError::BadRequest);
+    }
+
+    let deployment = start_deployment(&state, project_id.parse()?, git_ref, None, "Starting deployment...\n").await?;
+
+    let mut headers = HeaderMap::new();
+    headers.insert(header::CONTENT_TYPE, "application/json".parse().unwrap());
+
+    Ok((StatusCode::CREATED, headers, Json(deployment)))
+}
+
+/// Deploys the exact commit an earlier deployment ran. Its build directory is
+/// reused when it is still on disk, otherwise the commit is built again.
+pub async fn rollback_deployment(
+    State(state): State<AppState>,
+    Path((project_id, deployment_id)): Path<(String, String)>,
+) -> Result<(StatusCode, Json<Deployment>), AppError> {
     let conn = state.db.connect()?;
-    core::stop_deployment_with_conn(&conn, project_id.parse()?).await?;
+    let (target, commit_hash, build_id) = {
+        let mut rows = conn
+            .query(
+                "SELECT id, commit_hash, build_id FROM deployments WHERE project_id = ? AND id = ?",
+                [project_id.clone(), deployment_id],
+            )
+            .await?;
+        let row = rows.next().await?.ok_or(AppError::NotFound)?;
+        (row.get::<i64>(0)?, row.get::<Option<String>>(1)?, row.get::<Option<i64>>(2)?)
+    };
+    // A deployment that never got as far as a checkout has nothing to go back to
+    let commit_hash = commit_hash.filter(|c| !c.is_empty()).ok_or(AppError::BadRequest)?;
+    let build_id = build_id.unwrap_or(target);
+
+    let logs = format!("Rolling back to deployment {} at commit {}\n", target, commit_hash);
+    let deployment = start_deployment(&state, project_id.parse()?, Some(commit_hash), Some(build_id), &logs).await?;
+    Ok((StatusCode::CREATED, Json(deployment)))
+}
+
+/// Records a new pending deployment and starts deploying it in the background.
+async fn start_deployment(
+    state: &AppState,
+    project_id: i32,
+    git_ref: Option<String>,
+    build_id: Option<i64>,
+    logs: &str,
+) -> Result<Deployment, AppError> {
+    let conn = state.db.connect()?;
+    conn.execute("UPDATE projects SET stopped = 0 WHERE id = ?", [project_id]).await?;
 
     let deployment = Deployment {
         id: Some(0),  // Set temporary ID
-        project_id: project_id.parse()?,
+        project_id,
+        git_ref,
+        build_id,
         commit_hash: String::new(),
         status: STATUS_PENDING,
-        logs: String::from("Starting deployment...\n"),
+        pinned: false,
+        logs: logs.to_string(),
+        restart_count: 0,
+        oom_kills: 0,
+        port: None,
         created_at: String::new(),
     };
 
     conn.execute(
-        "INSERT INTO deployments (project_id, commit_hash, status, logs) 
-         VALUES (?, ?, ?, ?)",
+        "INSERT INTO deployments (project_id, git_ref, build_id, commit_hash, status, logs, sequence) 
+         VALUES (?, ?, ?, ?, ?, ?, (SELECT COALESCE(MAX(sequence), 0) + 1 FROM deployments))",
         (
             deployment.project_id,
+            deployment.git_ref.clone(),
+            deployment.build_id,
             deployment.commit_hash.clone(),
             deployment.status,
             deployment.logs.clone(),
@@ -194,38 +567,69 @@ pub async fn deploy(
     .await?;
     let deployment_id = conn.last_insert_rowid();
 
-    let mut headers = HeaderMap::new();
-    headers.insert(header::CONTENT_TYPE, "application/json".parse().unwrap());
-
     let deployment = Deployment {
         id: Some(deployment_id as i32),
         ..deployment
     };
 
+    let state = state.clone();
     tokio::spawn(async move {
-        let s = state.clone();
-        if let Err(e) = core::deploy(&s, project_id.parse().unwrap(), deployment_id).await {
+        if let Err(e) = core::deploy(&state, project_id, deployment_id).await {
             eprintln!("Deployment error: {:?}", e);
         }
     });
 
-    Ok((StatusCode::CREATED, headers, Json(deployment)))
+    Ok(deployment)
+}
+
+/// Starts a new revision of the project's running deployment on the same
+/// build, so that a changed environment or run command is picked up without
+/// fetching or building anything. The revision takes over once it is healthy,
+/// like any other deployment. Nothing happens when the project isn't running.
+async fn restart_running(state: &AppState, project_id: i32, reason: &str) -> Result<Option<Deployment>, AppError> {
+    let conn = state.db.connect()?;
+    let running = {
+        let mut rows = conn
+            .query(
+                "SELECT d.id, d.build_id, d.commit_hash FROM deployments d
+                 INNER JOIN projects p ON p.id = d.project_id
+                 WHERE d.project_id = ? AND d.status = ? AND p.stopped = 0
+                 ORDER BY d.sequence DESC LIMIT 1",
+                (project_id, STATUS_RUNNING),
+            )
+            .await?;
+        match rows.next().await? {
+            Some(row) => Some((row.get::<i64>(0)?, row.get::<Option<i64>>(1)?, row.get::<Option<String>>(2)?)),
+            None => None,
+        }
+    };
+    let Some((current, build_id, commit_hash)) = running else { return Ok(None) };
+    let build_id = build_id.unwrap_or(current);
+    // Whatever an earlier change started is outdated by this one
+    for cancelled in core::cancel_revisions(&conn, &state.logs, project_id, current, build_id).await? {
+        println!("Replacing deployment {} of project {}, its settings changed again", cancelled, project_id);
+    }
+
+    let logs = format!("{}, restarting deployment {} on the same build\n", reason, current);
+    let commit_hash = commit_hash.filter(|c| !c.is_empty());
+    let deployment = start_deployment(state, project_id, commit_hash, Some(build_id), &logs).await?;
+    Ok(Some(deployment))
 }
 
 pub async fn delete_project(
     State(state): State<AppState>,
     Path(id): Path<String>,
-) -> Result<StatusCode, AppError> {
+    Query(query): Query<DeleteQuery>,
+) -> Result<Json<DeleteReport>, AppError> {
+    let cleanup = core::DirectoryCleanup::parse(query.files.as_deref())?;
     let conn = state.db.connect()?;
 
     println!("Deleting project with id: {}", id);
     let id_as_int :i32 = id.parse()?;
-    conn.execute("DELETE FROM deployments WHERE project_id = ?", [id_as_int])
-        .await?;
-    conn.execute("DELETE FROM projects WHERE id = ?", [id_as_int])
-        .await?;
+    let report = core::delete_project(&conn, &state.logs, id_as_int, cleanup).await?;
+    state.routes.refresh(&conn).await;
 
-    Ok(StatusCode::OK)
+    Ok(Json(report))
 }
 
 pub async fn list_deployments(
@@ -236,7 +640,7 @@ pub async fn list_deployments(
 
     let mut rows = conn
         .query(
-            "SELECT id, project_id, commit_hash, status, created_at
+            "SELECT id, project_id, git_ref, build_id, commit_hash, status, pinned, created_at
              FROM deployments WHERE project_id = ?",
             [project_id],
         )
@@ -249,9 +653,12 @@ pub async fn list_deployments(
         let deployment = MiniDep {
             id: row.get(0)?,
             project_id: row.get(1)?,
-            commit_hash: row.get(2)?,
-            status: row.get(3)?,
-            created_at: row.get(4)?,
+            git_ref: row.get(2)?,
+            build_id: row.get(3)?,
+            commit_hash: row.get(4)?,
+            status: row.get(5)?,
+            pinned: row.get(6)?,
+            created_at: row.get(7)?,
         };
         deployments.push(deployment);
     }
@@ -264,7 +671,7 @@ pub async fn get_deployment(
     let conn = state.db.connect()?;
     let mut rows = conn
         .query(
-            "SELECT id, project_id, commit_hash, status, logs, created_at
+            "SELECT id, project_id, git_ref, build_id, commit_hash, status, pinned, logs, restart_count, oom_kills, port, created_at
              FROM deployments WHERE project_id = ? AND id = ?",
             [project_id, deployment_id],
         )
@@ -276,27 +683,242 @@ pub async fn get_deployment(
     let deployment = Deployment {
         id: row.get(0)?,
         project_id: row.get(1)?,
-        commit_hash: row.get(2)?,
-        status: row.get(3)?,
-        logs: row.get(4)?,
-        created_at: row.get(5)?,
+        git_ref: row.get(2)?,
+        build_id: row.get(3)?,
+        commit_hash: row.get(4)?,
+        status: row.get(5)?,
+        pinned: row.get(6)?,
+        logs: row.get(7)?,
+        restart_count: row.get(8)?,
+        oom_kills: row.get(9)?,
+        port: row.get(10)?,
+        created_at: row.get(11)?,
     };
     Ok(Json(deployment))
 }
 
+/// Pinned deployments are never pruned, whatever the retention policy says.
+pub async fn pin_deployment(
+    State(state): State<AppState>,
+    Path((project_id, deployment_id)): Path<(String, String)>,
+) -> Result<StatusCode, AppError> {
+    set_pinned(&state, &project_id, &deployment_id, true).await
+}
+
+pub async fn unpin_deployment(
+    State(state): State<AppState>,
+    Path((project_id, deployment_id)): Path<(String, String)>,
+) -> Result<StatusCode, AppError> {
+    set_pinned(&state, &project_id, &deployment_id, false).await
+}
+
+async fn set_pinned(state: &AppState, project_id: &str, deployment_id: &str, pinned: bool) -> Result<StatusCode, AppError> {
+    let conn = state.db.connect()?;
+    let updated = conn
+        .execute(
+            "UPDATE deployments SET pinned = ? WHERE project_id = ? AND id = ?",
+            libsql::params![pinned, project_id, deployment_id],
+        )
+        .await?;
+    if updated == 0 {
+        return Err(AppError::NotFound);
+    }
+    Ok(StatusCode::OK)
+}
+
+pub async fn prune_project(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+) -> Result<Json<PruneReport>, AppError> {
+    let conn = state.db.connect()?;
+    Ok(Json(core::prune_deployments(&conn, id.parse()?).await?))
+}
+
+pub async fn list_restarts(
+    State(state): State<AppState>,
+    Path((project_id, deployment_id)): Path<(String, String)>,
+) -> Result<Json<Vec<Restart>>, AppError> {
+    let conn = state.db.connect()?;
+    let mut rows = conn
+        .query(
+            "SELECT r.id, r.deployment_id, r.exit_code, r.signal, r.created_at
+             FROM deployment_restarts r
+             JOIN deployments d ON d.id = r.deployment_id
+             WHERE d.project_id = ? AND d.id = ?
+             ORDER BY r.id",
+            [project_id, deployment_id],
+        )
+        .await?;
+    let mut restarts = Vec::new();
+    while let Some(row) = rows.next().await? {
+        restarts.push(Restart {
+            id: row.get(0)?,
+            deployment_id: row.get(1)?,
+            exit_code: row.get(2)?,
+            signal: row.get(3)?,
+            created_at: row.get(4)?,
+        });
+    }
+    Ok(Json(restarts))
+}
+
+/// Resource usage of a deployment's run process group over the last hour,
+/// oldest first. Empty while it isn't running.
+pub async fn deployment_metrics(
+    State(state): State<AppState>,
+    Path((project_id, deployment_id)): Path<(String, String)>,
+    Query(query): Query<MetricsQuery>,
+) -> Result<Json<Vec<ProcessSample>>, AppError> {
+    let (deployment_id, _) = find_deployment(&state, &project_id, &deployment_id).await?;
+    Ok(Json(state.metrics.deployment(deployment_id, query.since)))
+}
+
+/// Looks up a deployment of a project, returning its id and log directory.
+async fn find_deployment(state: &AppState, project_id: &str, deployment_id: &str) -> Result<(i64, PathBuf), AppError> {
+    let conn = state.db.connect()?;
+    let mut rows = conn
+        .query(
+            "SELECT d.id, p.name FROM deployments d
+             JOIN projects p ON p.id = d.project_id
+             WHERE d.project_id = ? AND d.id = ?",
+            [project_id, deployment_id],
+        )
+        .await?;
+    let row = rows.next().await?.ok_or(AppError::NotFound)?;
+    let id = row.get(0)?;
+    let name: String = row.get(1)?;
+    Ok((id, logs::log_dir(&name, id)))
+}
+
+pub async fn read_logs(
+    State(state): State<AppState>,
+    Path((project_id, deployment_id)): Path<(String, String)>,
+    Query(query): Query<LogRangeQuery>,
+    Query(filter): Query<LogFilter>,
+) -> Result<Response, AppError> {
+    let (_, dir) = find_deployment(&state, &project_id, &deployment_id).await?;
+    let page = match query.line {
+        Some(line) => logs::read_lines(&dir, &filter, line, query.lines.unwrap_or(DEFAULT_LOG_LINES))?,
+        None => logs::read_page(
+            &dir,
+            &filter,
+            query.offset.unwrap_or(0),
+            query.length.unwrap_or(DEFAULT_LOG_LENGTH),
+        )?,
+    };
+    match query.format.as_deref() {
+        None | Some("json") => Ok(Json(page).into_response()),
+        Some("jsonl") => Ok((
+            [
+                ("content-type", "application/x-ndjson".to_string()),
+                ("x-log-offset", page.offset.to_string()),
+                ("x-log-next", page.next.to_string()),
+            ],
+            page.to_json_lines(),
+        )
+            .into_response()),
+        Some(_) => Err(AppError::BadRequest),
+    }
+}
+
+pub async fn stream_logs(
+    State(state): State<AppState>,
+    Path((project_id, deployment_id)): Path<(String, String)>,
+    Query(query): Query<LogQuery>,
+    Query(filter): Query<LogFilter>,
+    headers: HeaderMap,
+) -> Result<Sse<impl Stream<Item = Result<Event, Infallible>>>, AppError> {
+    let (deployment_id, dir) = find_deployment(&state, &project_id, &deployment_id).await?;
+    // EventSource reconnects with the id of the last event it saw
+    let since = query.since.or_else(|| {
+        headers
+            .get("last-event-id")
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.parse().ok())
+    });
+    let chunks = logs::follow(&state, dir, deployment_id, since.unwrap_or(0)).await?;
+
+    // One JSON record per data line; chunks without matching records are skipped
+    let events = ReceiverStream::new(chunks).filter_map(move |chunk| {
+        let page = LogPage::from_chunk(&chunk, &filter);
+        let lines = page.to_json_lines();
+        (!lines.is_empty()).then(|| Ok(Event::default().id(page.next.to_string()).data(lines.trim_end())))
+    });
+    Ok(Sse::new(events).keep_alive(KeepAlive::default()))
+}
+
+pub async fn ws_logs(
+    State(state): State<AppState>,
+    Path((project_id, deployment_id)): Path<(String, String)>,
+    Query(query): Query<LogQuery>,
+    Query(filter): Query<LogFilter>,
+    ws: WebSocketUpgrade,
+) -> Result<Response, AppError> {
+    let (deployment_id, dir) = find_deployment(&state, &project_id, &deployment_id).await?;
+    let mut chunks = logs::follow(&state, dir, deployment_id, query.since.unwrap_or(0)).await?;
+
+    Ok(ws.on_upgrade(move |mut socket| async move {
+        loop {
+            tokio::select! {
+                chunk = chunks.recv() => {
+                    let Some(chunk) = chunk else { break };
+                    let page = LogPage::from_chunk(&chunk, &filter);
+                    if page.records.is_empty() {
+                        continue;
+                    }
+                    let Ok(text) = serde_json::to_string(&page) else { break };
+                    if socket.send(Message::Text(text.into())).await.is_err() {
+                        break;
+                    }
+                }
+                message = socket.recv() => match message {
+                    Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                    Some(Ok(_)) => {}
+                },
+            }
+        }
+    }))
+}
+
+/// Stops every deployment of a project and keeps it stopped, across node
+/// restarts too, until it is started or deployed again.
+pub async fn stop_project(
+    State(state): State<AppState>,
+    Path(project_id): Path<String>,
+) -> Result<Json<StopReport>, AppError> {
+    let conn = state.db.connect()?;
+    let project_id: i32 = project_id.parse()?;
+    if conn.execute("UPDATE projects SET stopped = 1 WHERE id = ?", [project_id]).await? == 0 {
+        return Err(AppError::NotFound);
+    }
+    let deployments = core::stop_deployment_with_conn(&conn, &state.logs, project_id).await?;
+    state.routes.refresh(&conn).await;
+    Ok(Json(StopReport { deployments }))
+}
+
+/// Runs the project's last build again without rebuilding it.
+pub async fn start_project(
+    State(state): State<AppState>,
+    Path(project_id): Path<String>,
+) -> Result<Json<Deployment>, AppError> {
+    let deployment_id = core::start_project(&state, project_id.parse()?).await?;
+    get_deployment((State(state), Path((project_id, deployment_id.to_string())))).await
+}
+
 pub async fn restart_deployment(
     State(state): State<AppState>,
     Path((project_id, deployment_id)): Path<(String, String)>,
 ) -> Result<StatusCode, AppError> {
     let conn = state.db.connect()?;
     
-    conn.execute(
-        "UPDATE deployments SET status = ? WHERE project_id = ? AND status = ?",
-        (STATUS_STOPPED, project_id.clone(), STATUS_RUNNING)
-    ).await?;
+    // Only the deployment itself has to make way, as it is rebuilt in place. Whatever
+    // else runs keeps serving until the restarted one is up and takes over.
+    core::stop_deployment_by_id(&conn, &state.logs, project_id.parse()?, deployment_id.parse()?).await?;
 
+    conn.execute("UPDATE projects SET stopped = 0 WHERE id = ?", [project_id.clone()]).await?;
     conn.execute(
-        "UPDATE deployments SET status = ?, logs = 'Restarting deployment...\n' WHERE id = ?",
+        "UPDATE deployments SET status = ?, logs = 'Restarting deployment...\n',
+         sequence = (SELECT MAX(sequence) + 1 FROM deployments) WHERE id = ?",
         (STATUS_PENDING, deployment_id.clone())
     ).await?;
 
@@ -309,30 +931,178 @@ pub async fn restart_deployment(
     Ok(StatusCode::OK)
 }
 
-pub async fn update() -> Result<StatusCode, AppError> {
-    tokio::spawn(async move {
-        println!("Updating edgezone-node...");
-        let output = tokio::process::Command::new("sh")
-            .arg("-c")
-            .arg("curl https://raw.githubusercontent.com/Smartlinuxcoder/edgezone/refs/heads/main/install.sh | bash")
-            .output()
-            .await;
-        match output {
-            Ok(output) if output.status.success() => {
-                println!("Update successful, restarting...");
-                std::process::exit(0);
-            }
-            Ok(output) => {
-                let error = String::from_utf8_lossy(&output.stderr);
-                eprintln!("Update failed: {}", error);
-            }
-            Err(e) => {
-                eprintln!("Update failed: {}", e);
-            }
-        }
-    });
+/// Starts updating the node to the latest release. The body is optional,
+/// `{"force": true}` installs the release even if it isn't newer.
+pub async fn update(
+    State(state): State<AppState>,
+    body: Bytes,
+) -> Result<(StatusCode, Json<UpdateStatus>), AppError> {
+    let request: UpdateRequest = if body.is_empty() {
+        UpdateRequest { force: None }
+    } else {
+        serde_json::from_slice(&body).map_err(|_| AppError::BadRequest)?
+    };
+    let status = state.updates.start(request.force.unwrap_or(false))?;
+    Ok((StatusCode::ACCEPTED, Json(status)))
+}
+
+pub async fn update_status() -> Json<UpdateStatus> {
+    Json(UpdateStatus::load())
+}
+
+/// The project's plain environment variables.
+pub async fn get_env(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+) -> Result<Json<EnvVars>, AppError> {
+    let conn = state.db.connect()?;
+    Ok(Json(env_vars::load(&conn, id.parse()?).await?))
+}
+
+/// Imports variables in dotenv syntax, on top of the existing ones or, with
+/// `?replace=true`, in their place. Returns all of them.
+pub async fn import_env(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    Query(query): Query<ImportEnvQuery>,
+    body: String,
+) -> Result<Json<EnvVars>, AppError> {
+    let project_id = id.parse()?;
+    let imported = env_vars::parse(&body).map_err(|_| AppError::BadRequest)?;
+    let conn = state.db.connect()?;
+    let old = env_vars::load(&conn, project_id).await?;
+    let mut vars = if query.replace.unwrap_or(false) { EnvVars::new() } else { old.clone() };
+    vars.extend(imported);
+    env_vars::store(&conn, project_id, &vars).await?;
+    if vars != old {
+        restart_running(&state, project_id, "The environment changed").await?;
+    }
+    Ok(Json(vars))
+}
+
+pub async fn get_env_var(
+    State(state): State<AppState>,
+    Path((id, key)): Path<(String, String)>,
+) -> Result<Json<EnvVar>, AppError> {
+    let conn = state.db.connect()?;
+    let vars = env_vars::load(&conn, id.parse()?).await?;
+    let value = vars.get(&key).ok_or(AppError::NotFound)?.clone();
+    Ok(Json(EnvVar { key: Some(key), value }))
+}
+
+/// Sets one variable, leaving the others and the rest of the project alone.
+/// The running deployment is restarted with it, as for any env change.
+pub async fn put_env_var(
+    State(state): State<AppState>,
+    Path((id, key)): Path<(String, String)>,
+    Json(var): Json<EnvVar>,
+) -> Result<Json<EnvVar>, AppError> {
+    let project_id = id.parse()?;
+    let conn = state.db.connect()?;
+    let mut vars = env_vars::load(&conn, project_id).await?;
+    let old = vars.insert(key.clone(), var.value.clone());
+    env_vars::store(&conn, project_id, &vars).await?;
+    if old.as_ref() != Some(&var.value) {
+        restart_running(&state, project_id, &format!("{} changed", key)).await?;
+    }
+    Ok(Json(EnvVar { key: Some(key), value: var.value }))
+}
+
+pub async fn delete_env_var(
+    State(state): State<AppState>,
+    Path((id, key)): Path<(String, String)>,
+) -> Result<StatusCode, AppError> {
+    let project_id = id.parse()?;
+    let conn = state.db.connect()?;
+    let mut vars = env_vars::load(&conn, project_id).await?;
+    vars.remove(&key).ok_or(AppError::NotFound)?;
+    env_vars::store(&conn, project_id, &vars).await?;
+    restart_running(&state, project_id, &format!("{} was removed", key)).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// The project's secrets, with their values masked.
+pub async fn list_secrets(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+) -> Result<Json<Vec<Secret>>, AppError> {
+    let conn = state.db.connect()?;
+    Ok(Json(secrets::list_secrets(&conn, id.parse()?).await?))
+}
 
-    Ok(StatusCode::CREATED)
+/// Sets a secret, restarting the running deployment to pass it on. The value
+/// can't be read back.
+pub async fn put_secret(
+    State(state): State<AppState>,
+    Path((id, key)): Path<(String, String)>,
+    Json(secret): Json<Secret>,
+) -> Result<Json<Secret>, AppError> {
+    let project_id = id.parse()?;
+    let conn = state.db.connect()?;
+    let secret = secrets::set_secret(&conn, project_id, &key, &secret.value).await?;
+    restart_running(&state, project_id, &format!("Secret {} changed", key)).await?;
+    Ok(Json(secret))
+}
+
+pub async fn delete_secret(
+    State(state): State<AppState>,
+    Path((id, key)): Path<(String, String)>,
+) -> Result<StatusCode, AppError> {
+    let project_id = id.parse()?;
+    let conn = state.db.connect()?;
+    secrets::delete_secret(&conn, project_id, &key).await?;
+    restart_running(&state, project_id, &format!("Secret {} was removed", key)).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub async fn list_webhooks(State(state): State<AppState>) -> Result<Json<Vec<Webhook>>, AppError> {
+    let conn = state.db.connect()?;
+    Ok(Json(events::list_webhooks(&conn).await?))
+}
+
+/// Registers a webhook. The response is the only place its secret is shown.
+pub async fn create_webhook(
+    State(state): State<AppState>,
+    Json(webhook): Json<Webhook>,
+) -> Result<(StatusCode, Json<Webhook>), AppError> {
+    let conn = state.db.connect()?;
+    Ok((StatusCode::CREATED, Json(events::create_webhook(&conn, webhook).await?)))
+}
+
+pub async fn delete_webhook(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+) -> Result<StatusCode, AppError> {
+    let conn = state.db.connect()?;
+    events::delete_webhook(&conn, id.parse()?).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// The latest deliveries of a webhook, newest first, to see why it isn't getting through.
+pub async fn list_deliveries(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    Query(query): Query<DeliveriesQuery>,
+) -> Result<Json<Vec<Delivery>>, AppError> {
+    let conn = state.db.connect()?;
+    let limit = query.limit.unwrap_or(DEFAULT_DELIVERIES).clamp(1, MAX_DELIVERIES);
+    Ok(Json(events::list_deliveries(&conn, id.parse()?, limit).await?))
+}
+
+/// Load, memory, disk and temperature of the node over the last hour, oldest first.
+pub async fn node_metrics(
+    State(state): State<AppState>,
+    Query(query): Query<MetricsQuery>,
+) -> Json<Vec<NodeSample>> {
+    Json(state.metrics.node(query.since))
+}
+
+/// The latest node and deployment samples for Prometheus to scrape.
+pub async fn prometheus_metrics(State(state): State<AppState>) -> impl IntoResponse {
+    (
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        state.metrics.prometheus(),
+    )
 }
 
 pub async fn info() -> Result<Json<Info>, AppError> {
@@ -1,248 +1,1814 @@
-use std::{fs, process::Stdio};
-use super::super::{db, error::AppError};
-use super::Project;
-use tokio::io::AsyncBufReadExt;
-use super::{STATUS_PENDING, STATUS_INSTALLING, STATUS_BUILDING, STATUS_RUNNING, STATUS_FAILED, STATUS_STOPPED};
+use std::{fs, io::{Read, Seek, SeekFrom, Write}};
+use super::super::error::AppError;
+use super::{DeleteReport, Project, PruneReport};
+use super::{STATUS_PENDING, STATUS_INSTALLING, STATUS_BUILDING, STATUS_RUNNING, STATUS_FAILED, STATUS_STOPPED, STATUS_STARTING, STATUS_CRASHLOOP};
 use crate::db::AppState;
+use crate::env_vars;
+use crate::events::{self, EventKind};
+use crate::logs::{DeploymentLog, LogHub, LogLevel, LogPhase, LogStream};
+use crate::cgroup::{self, Cgroup, Limits};
+use crate::ports;
+use crate::proxy::Routes;
+use crate::sandbox::{Isolation, Sandbox};
+use crate::secrets;
+use std::os::unix::{fs::{MetadataExt, OpenOptionsExt, PermissionsExt}, process::ExitStatusExt};
+use nix::{errno::Errno, fcntl::OFlag, sys::{signal::{killpg, Signal}, wait::{waitpid, WaitPidFlag, WaitStatus}}, unistd::Pid};
 
-async fn kill_process(pid: i32) -> Result<(), AppError> {
-    let _ = tokio::process::Command::new("kill")
-        .arg("-15")
-        .arg(&pid.to_string())
-        .output()
-        .await;
-
-    tokio::time::sleep(tokio::time::Duration::from_secs(2)).await;
-    print!("Killing process with pid: {}\n", pid);
-    let _ = tokio::process::Command::new("kill")
-/*         .arg("-9")
- */        .arg(&pid.to_string())
-        .output()
-        .await;
+const DEFAULT_HEALTHCHECK_TIMEOUT: i32 = 5000;
+const HEALTHCHECK_INTERVAL: tokio::time::Duration = tokio::time::Duration::from_millis(500);
+const DEFAULT_MAX_RESTARTS: i32 = 5;
+const DEFAULT_STOP_TIMEOUT: i32 = 10000;
+const STOP_POLL_INTERVAL: tokio::time::Duration = tokio::time::Duration::from_millis(100);
+const RESTART_BACKOFF_BASE: tokio::time::Duration = tokio::time::Duration::from_secs(1);
+const RESTART_BACKOFF_MAX: tokio::time::Duration = tokio::time::Duration::from_secs(60);
+/// A process that stayed up this long gets its restart budget back.
+const STABLE_RUN: tokio::time::Duration = tokio::time::Duration::from_secs(60);
+/// Written to a deployment directory once its checkout is installed and built,
+/// holding the commit it was built from.
+const BUILT_MARKER: &str = "built";
+/// The bare repository inside a project directory that deployments check out from.
+const MIRROR_DIR: &str = ".mirror";
+/// One lock per mirror, so that a slow fetch only holds up the project it is for.
+static MIRROR_LOCKS: std::sync::Mutex<std::collections::BTreeMap<String, std::sync::Arc<tokio::sync::Mutex<()>>>> =
+    std::sync::Mutex::new(std::collections::BTreeMap::new());
+const DEFAULT_KEEP_DEPLOYMENTS: i32 = 5;
+const OUTPUT_POLL_INTERVAL: tokio::time::Duration = tokio::time::Duration::from_millis(250);
+const OUTPUT_READ_SIZE: u64 = 1024 * 1024;
+const OUTPUT_TRUNCATE_SIZE: u64 = 256 * 1024;
+/// Changes with every boot, so pids recorded before one can be told apart.
+const BOOT_ID_FILE: &str = "/proc/sys/kernel/random/boot_id";
+const ADOPTED_POLL_INTERVAL: tokio::time::Duration = tokio::time::Duration::from_secs(1);
+/// Where deleted projects' directories are moved to when they are archived.
+const ARCHIVE_DIR: &str = "archive";
 
+#[derive(Clone, Copy, PartialEq)]
+pub enum RestartPolicy {
+    Never,
+    OnFailure,
+    Always,
+}
+
+impl RestartPolicy {
+    pub fn parse(value: Option<&str>) -> Result<Self, AppError> {
+        match value.map(str::trim) {
+            None | Some("") | Some("never") => Ok(RestartPolicy::Never),
+            Some("on-failure") => Ok(RestartPolicy::OnFailure),
+            Some("always") => Ok(RestartPolicy::Always),
+            Some(_) => Err(AppError::BadRequest),
+        }
+    }
+}
+
+/// Stops a deployment's whole process group: SIGTERM first, then SIGKILL if
+/// it is still around after the grace period. Each step ends up in the logs.
+async fn kill_process_group(log: &DeploymentLog, pgid: i32, grace_ms: i32) -> Result<(), AppError> {
+    let pgid = Pid::from_raw(pgid);
+    println!("Stopping process group {} for deployment {}", pgid, log.deployment_id);
+
+    if let Err(e) = killpg(pgid, Signal::SIGTERM) {
+        let msg = match e {
+            Errno::ESRCH => format!("Process group {} had already exited", pgid),
+            e => format!("Failed to send SIGTERM to process group {}: {}", pgid, e),
+        };
+        log_warning(log, LogPhase::Stop, &msg).await?;
+        return Ok(());
+    }
+    update_logs(log, LogPhase::Stop, &format!("Sent SIGTERM to process group {}, waiting up to {}ms", pgid, grace_ms)).await?;
+
+    let started = tokio::time::Instant::now();
+    let grace = tokio::time::Duration::from_millis(grace_ms.max(0) as u64);
+    while started.elapsed() < grace {
+        tokio::time::sleep(STOP_POLL_INTERVAL).await;
+        if killpg(pgid, None) == Err(Errno::ESRCH) {
+            update_logs(log, LogPhase::Stop, &format!("Process group {} exited after {}ms", pgid, started.elapsed().as_millis())).await?;
+            return Ok(());
+        }
+    }
+
+    match killpg(pgid, Signal::SIGKILL) {
+        Ok(()) => log_warning(log, LogPhase::Stop, &format!("Process group {} still running after {}ms, sent SIGKILL", pgid, grace_ms)).await?,
+        Err(Errno::ESRCH) => update_logs(log, LogPhase::Stop, &format!("Process group {} exited", pgid)).await?,
+        Err(e) => log_error(log, LogPhase::Stop, &format!("Failed to send SIGKILL to process group {}: {}", pgid, e)).await?,
+    }
     Ok(())
 }
 
+/// Whether `git_ref` is something we can hand to git as a branch, tag or
+/// commit, following `git check-ref-format`. Options, refspecs like
+/// `+a:b` and revision syntax like `main~1` are not.
+pub fn valid_git_ref(git_ref: &str) -> bool {
+    !git_ref.is_empty()
+        && !git_ref.starts_with('-')
+        && !git_ref.ends_with('/')
+        && !git_ref.ends_with('.')
+        && !git_ref.contains("..")
+        && !git_ref.contains("@{")
+        && git_ref != "@"
+        && !git_ref.chars().any(|c| c.is_whitespace() || c.is_control() || ":+~^?*[\\".contains(c))
+        && git_ref
+            .split('/')
+            .all(|part| !part.is_empty() && !part.starts_with('.') && !part.ends_with(".lock"))
+}
+
+/// Size of a directory tree in bytes, without following symlinks.
+fn dir_size(path: &std::path::Path) -> u64 {
+    let Ok(metadata) = fs::symlink_metadata(path) else {
+        return 0;
+    };
+    if !metadata.is_dir() {
+        return metadata.len();
+    }
+    fs::read_dir(path)
+        .into_iter()
+        .flatten()
+        .flatten()
+        .map(|entry| dir_size(&entry.path()))
+        .sum()
+}
+
+/// Deletes the deployments of a project that fall outside its retention
+/// policy, directories and rows alike. The last `keep_deployments` are kept,
+/// as is anything still live, pinned, or whose build a kept deployment runs.
+pub async fn prune_deployments(conn: &libsql::Connection, proj_id: i32) -> Result<PruneReport, AppError> {
+    conn.query("PRAGMA busy_timeout = 10000", ()).await?;
+    // Rows keep their statement, and with it a read transaction, open until dropped
+    let (project_name, keep) = {
+        let mut rows = conn
+            .query("SELECT name, keep_deployments FROM projects WHERE id = ?", [proj_id])
+            .await?;
+        let row = rows.next().await?.ok_or(AppError::NotFound)?;
+        let keep = row.get::<Option<i32>>(1)?.unwrap_or(DEFAULT_KEEP_DEPLOYMENTS).max(0);
+        (row.get::<String>(0)?, keep)
+    };
+
+    let mut rows = conn
+        .query(
+            "SELECT id, status, pinned, build_id FROM deployments WHERE project_id = ? ORDER BY id DESC",
+            [proj_id],
+        )
+        .await?;
+    let mut kept = Vec::new();
+    let mut candidates = Vec::new();
+    let mut position = 0;
+    while let Some(row) = rows.next().await? {
+        let id: i64 = row.get(0)?;
+        let status: i32 = row.get(1)?;
+        let pinned = row.get::<Option<bool>>(2)?.unwrap_or(false);
+        let build_id: Option<i64> = row.get(3)?;
+        let live = !matches!(status, STATUS_STOPPED | STATUS_FAILED | STATUS_CRASHLOOP);
+        if position < keep || live || pinned {
+            kept.push((id, build_id));
+        } else {
+            candidates.push(id);
+        }
+        position += 1;
+    }
+    // A rollback runs from an older deployment's build, which has to stay around with it
+    let builds_in_use: Vec<i64> = kept.iter().filter_map(|(_, build_id)| *build_id).collect();
+
+    let mut report = PruneReport { deployments: Vec::new(), bytes: 0 };
+    for deployment_id in candidates {
+        if builds_in_use.contains(&deployment_id) {
+            continue;
+        }
+        let path = std::path::PathBuf::from(format!("projects/{}/{}", project_name, deployment_id));
+        report.bytes += dir_size(&path);
+        if path.exists() {
+            fs::remove_dir_all(&path)?;
+        }
+        cgroup::remove(deployment_id);
+        conn.execute("DELETE FROM deployment_restarts WHERE deployment_id = ?", [deployment_id]).await?;
+        conn.execute("DELETE FROM deployments WHERE id = ?", [deployment_id]).await?;
+        report.deployments.push(deployment_id);
+    }
+
+    if !report.deployments.is_empty() {
+        // Forget the worktrees whose directories just went away
+        let mirror = format!("projects/{}/{}", project_name, MIRROR_DIR);
+        git_quiet(&["--git-dir", &mirror, "worktree", "prune"]).await;
+        println!("Pruned {} deployments of {}, reclaimed {} bytes", report.deployments.len(), project_name, report.bytes);
+    }
+    Ok(report)
+}
+
+/// What happens to a project's directory when the project is deleted.
+#[derive(Clone, Copy, PartialEq)]
+pub enum DirectoryCleanup {
+    Keep,
+    Archive,
+    Remove,
+}
+
+impl DirectoryCleanup {
+    pub fn parse(value: Option<&str>) -> Result<Self, AppError> {
+        match value.map(str::trim) {
+            None | Some("") | Some("keep") => Ok(DirectoryCleanup::Keep),
+            Some("archive") => Ok(DirectoryCleanup::Archive),
+            Some("remove") => Ok(DirectoryCleanup::Remove),
+            Some(_) => Err(AppError::BadRequest),
+        }
+    }
+}
+
+/// Whether `name` can be used as a directory under `projects/`.
+pub fn valid_project_name(name: &str) -> bool {
+    !name.is_empty()
+        && !name.starts_with('.')
+        && !name.chars().any(|c| c == '/' || c == '\\' || c.is_control())
+}
+
+/// Stops every deployment of a project, deletes its rows, webhooks included,
+/// and then keeps, archives or removes its directory.
+pub async fn delete_project(conn: &libsql::Connection, hub: &LogHub, proj_id: i32, cleanup: DirectoryCleanup) -> Result<DeleteReport, AppError> {
+    conn.query("PRAGMA busy_timeout = 10000", ()).await?;
+    let name = {
+        let mut rows = conn.query("SELECT name FROM projects WHERE id = ?", [proj_id]).await?;
+        let row = rows.next().await?.ok_or(AppError::NotFound)?;
+        row.get::<String>(0)?
+    };
+
+    let stopped = stop_deployment_with_conn(conn, hub, proj_id).await?;
+    conn.execute(
+        "DELETE FROM deployment_restarts WHERE deployment_id IN (SELECT id FROM deployments WHERE project_id = ?)",
+        [proj_id],
+    ).await?;
+    let deployments = conn.execute("DELETE FROM deployments WHERE project_id = ?", [proj_id]).await?;
+    conn.execute("DELETE FROM secrets WHERE project_id = ?", [proj_id]).await?;
+    conn.execute(
+        "DELETE FROM webhook_deliveries WHERE webhook_id IN (SELECT id FROM webhooks WHERE project_id = ?)",
+        [proj_id],
+    ).await?;
+    conn.execute("DELETE FROM webhooks WHERE project_id = ?", [proj_id]).await?;
+    conn.execute("DELETE FROM projects WHERE id = ?", [proj_id]).await?;
+
+    let mut report = DeleteReport {
+        id: proj_id,
+        name: name.clone(),
+        stopped,
+        deployments,
+        files: "kept".to_string(),
+        archive: None,
+        bytes: 0,
+    };
+    let path = std::path::PathBuf::from(format!("projects/{}", name));
+    // Names from before they were validated could point anywhere, leave those alone
+    if !valid_project_name(&name) || !path.exists() {
+        return Ok(report);
+    }
+    report.bytes = dir_size(&path);
+    match cleanup {
+        DirectoryCleanup::Keep => {}
+        DirectoryCleanup::Archive => {
+            fs::create_dir_all(ARCHIVE_DIR)?;
+            let archive = format!("{}/{}-{}", ARCHIVE_DIR, name, chrono::Utc::now().format("%Y%m%d%H%M%S"));
+            fs::rename(&path, &archive)?;
+            report.files = "archived".to_string();
+            report.archive = Some(archive);
+        }
+        DirectoryCleanup::Remove => {
+            fs::remove_dir_all(&path)?;
+            report.files = "removed".to_string();
+        }
+    }
+    println!("Deleted project {}, {} its {} bytes of files", name, report.files, report.bytes);
+    Ok(report)
+}
+
 pub async fn new_project(path: &String) -> Result<(), AppError> {
-    fs::create_dir_all(format!("projects/{}", path))?;
+    let path = format!("projects/{}", path);
+    fs::create_dir_all(&path)?;
+    // Isolated projects get their group let in once they are deployed, nobody else does
+    fs::set_permissions(&path, fs::Permissions::from_mode(0o700))?;
     Ok(())
 }
 
-pub async fn stop_deployment_with_conn(conn: &libsql::Connection, proj_id: i32) -> Result<(), AppError> {
+pub async fn stop_deployment_with_conn(conn: &libsql::Connection, hub: &LogHub, proj_id: i32) -> Result<Vec<i64>, AppError> {
+    stop_deployments_before(conn, hub, proj_id, None).await
+}
+
+/// Stops one deployment of a project, leaving the others alone.
+pub async fn stop_deployment_by_id(conn: &libsql::Connection, hub: &LogHub, proj_id: i32, deployment_id: i64) -> Result<Vec<i64>, AppError> {
+    conn.query("PRAGMA busy_timeout = 10000", ()).await?;
+    let mut rows = conn.query(
+        "SELECT d.id, p.name, p.stop_timeout FROM deployments d
+         JOIN projects p ON p.id = d.project_id
+         WHERE d.project_id = ? AND d.id = ? AND d.status NOT IN (?, ?)",
+        (proj_id, deployment_id, STATUS_STOPPED, STATUS_FAILED)
+    ).await?;
+    let mut deployments = Vec::new();
+    while let Some(row) = rows.next().await? {
+        let stop_timeout = row.get::<Option<i32>>(2)?.unwrap_or(DEFAULT_STOP_TIMEOUT);
+        deployments.push((row.get::<i64>(0)?, row.get::<String>(1)?, stop_timeout));
+    }
+    drop(rows);
+    stop_listed(conn, hub, deployments).await
+}
+
+/// Stops every live deployment of a project started before `keep`, which is
+/// how a freshly promoted deployment takes over from the previous ones. Ones
+/// started later that are still on their way up are left to take over in
+/// turn. Without `keep` every deployment is stopped. Returns the deployments
+/// that were stopped.
+pub async fn stop_deployments_before(conn: &libsql::Connection, hub: &LogHub, proj_id: i32, keep: Option<i64>) -> Result<Vec<i64>, AppError> {
     conn.query("PRAGMA busy_timeout = 10000", ()).await?;
     // Get running deployments
     let mut rows = conn.query(
-        "SELECT d.id, p.name FROM deployments d 
+        "SELECT d.id, p.name, p.stop_timeout FROM deployments d 
          JOIN projects p ON p.id = d.project_id 
-         WHERE d.project_id = ? AND d.status != ?",
-        (proj_id, STATUS_STOPPED)
+         WHERE d.project_id = ? AND d.status NOT IN (?, ?)
+         AND (? IS NULL OR d.sequence < (SELECT sequence FROM deployments WHERE id = ?))",
+        libsql::params![proj_id, STATUS_STOPPED, STATUS_FAILED, keep, keep]
     ).await?;
-    
+    let mut deployments = Vec::new();
     while let Some(row) = rows.next().await? {
-        let deployment_id: i64 = row.get(0)?;
-        let project_name: String = row.get(1)?;
-        
+        let stop_timeout = row.get::<Option<i32>>(2)?.unwrap_or(DEFAULT_STOP_TIMEOUT);
+        deployments.push((row.get::<i64>(0)?, row.get::<String>(1)?, stop_timeout));
+    }
+    drop(rows);
+    stop_listed(conn, hub, deployments).await
+}
+
+/// Stops the revisions of a project that are still being started on build
+/// `build_id` and were started after deployment `after`, because a newer one
+/// replaces them.
+pub async fn cancel_revisions(conn: &libsql::Connection, hub: &LogHub, proj_id: i32, after: i64, build_id: i64) -> Result<Vec<i64>, AppError> {
+    conn.query("PRAGMA busy_timeout = 10000", ()).await?;
+    let mut rows = conn.query(
+        "SELECT d.id, p.name, p.stop_timeout FROM deployments d
+         JOIN projects p ON p.id = d.project_id
+         WHERE d.project_id = ? AND d.sequence > (SELECT sequence FROM deployments WHERE id = ?)
+         AND d.build_id = ? AND d.status IN (?, ?, ?, ?)",
+        libsql::params![proj_id, after, build_id, STATUS_PENDING, STATUS_INSTALLING, STATUS_BUILDING, STATUS_STARTING]
+    ).await?;
+    let mut deployments = Vec::new();
+    while let Some(row) = rows.next().await? {
+        let stop_timeout = row.get::<Option<i32>>(2)?.unwrap_or(DEFAULT_STOP_TIMEOUT);
+        deployments.push((row.get::<i64>(0)?, row.get::<String>(1)?, stop_timeout));
+    }
+    drop(rows);
+    stop_listed(conn, hub, deployments).await
+}
+
+/// Stops the given deployments, each with its project's name and stop timeout.
+async fn stop_listed(conn: &libsql::Connection, hub: &LogHub, deployments: Vec<(i64, String, i32)>) -> Result<Vec<i64>, AppError> {
+    // Mark them stopped first so their supervisors don't restart them
+    for (deployment_id, _, _) in &deployments {
+        conn.execute(
+            "UPDATE deployments SET status = ? WHERE id = ? AND status NOT IN (?, ?)",
+            (STATUS_STOPPED, *deployment_id, STATUS_STOPPED, STATUS_FAILED)
+        ).await?;
+    }
+
+    let stopped = deployments.iter().map(|(deployment_id, _, _)| *deployment_id).collect();
+    for (deployment_id, project_name, stop_timeout) in deployments {
+        events::emit(conn, deployment_id, EventKind::Stopped, "Deployment stopped").await;
         let path = format!("projects/{}/{}", project_name, deployment_id);
-        let pid_file = format!("{}/pid", path);
+
+        if let Some(pgid) = running_pgid(&path) {
+            let log = DeploymentLog::new(conn, hub, &project_name, deployment_id);
+            let _ = kill_process_group(&log, pgid, stop_timeout).await;
+        }
         
-        if let Ok(content) = fs::read_to_string(&pid_file) {
-            if let Ok(pid) = content.trim().parse::<i32>() {
-                println!("Stopping process {} for deployment {}", pid, deployment_id);
-                let _ = kill_process(pid).await;
+        let _ = fs::remove_file(format!("{}/pid", path));
+    }
+
+    Ok(stopped)
+}
+
+/// The sandbox an isolated project's commands run in, with its working
+/// directory handed over to it. None when the project isn't isolated.
+async fn sandbox(log: &DeploymentLog, project: &Project, work_dir: &str, phase: LogPhase) -> Result<Option<Sandbox>, AppError> {
+    let isolation = Isolation::parse(project.isolation.as_deref())?;
+    let project_dir = format!("projects/{}", project.name);
+    let sandbox = Sandbox::new(isolation, project.id.unwrap_or_default(), &project_dir, work_dir)
+        .and_then(|sandbox| {
+            if let Some(sandbox) = &sandbox {
+                sandbox.prepare()?;
             }
+            Ok(sandbox)
+        });
+    if let Err(AppError::Internal(e)) = &sandbox {
+        update_status(&log.conn, log.deployment_id, STATUS_FAILED).await?;
+        log_error(log, phase, e).await?;
+    }
+    sandbox
+}
+
+/// The cgroup holding a deployment's processes to the project's resource
+/// limits. None when it has none, or when they can't be applied on this node,
+/// which only gets a warning.
+async fn cgroup(log: &DeploymentLog, project: &Project, phase: LogPhase) -> Result<Option<Cgroup>, AppError> {
+    let limits = Limits {
+        memory: project.memory_limit,
+        cpu: project.cpu_limit,
+        pids: project.pids_limit,
+    };
+    match Cgroup::create(log.deployment_id, &limits) {
+        Ok(cgroup) => Ok(cgroup),
+        Err(e) => {
+            log_warning(log, phase, &format!("Resource limits are not applied: {}", e)).await?;
+            Ok(None)
         }
-        
-        let _ = fs::remove_file(pid_file);
     }
+}
 
-    conn.execute(
-        "UPDATE deployments SET status = ? WHERE project_id = ? AND status != ?",
-        (STATUS_STOPPED, proj_id, STATUS_STOPPED)
+/// Gives the deployment a free port, which its commands get as PORT. Running
+/// out of ports fails the deployment.
+async fn port(log: &DeploymentLog, phase: LogPhase) -> Result<u16, AppError> {
+    match ports::assign(&log.conn, log.deployment_id).await {
+        Ok(port) => Ok(port),
+        Err(e) => {
+            update_status(&log.conn, log.deployment_id, STATUS_FAILED).await?;
+            if let AppError::Internal(message) = &e {
+                log_error(log, phase, message).await?;
+            }
+            Err(e)
+        }
+    }
+}
+
+/// The project's secrets in the clear. One that can't be decrypted fails the
+/// deployment rather than have it start without.
+async fn secrets(log: &DeploymentLog, project: &Project, phase: LogPhase) -> Result<Vec<(String, String)>, AppError> {
+    match secrets::load(&log.conn, project.id.unwrap_or_default()).await {
+        Ok(secrets) => Ok(secrets),
+        Err(e) => {
+            update_status(&log.conn, log.deployment_id, STATUS_FAILED).await?;
+            if let AppError::Internal(message) = &e {
+                log_error(log, phase, message).await?;
+            }
+            Err(e)
+        }
+    }
+}
+
+/// Hands a command its port and the project's secrets. They only ever live in
+/// the process environment, never in a file in the checkout. Called after the
+/// sandbox, which starts from an empty environment.
+fn set_env(command: &mut tokio::process::Command, port: u16, secrets: &[(String, String)]) {
+    command.envs(secrets.iter().map(|(key, value)| (key, value)));
+    command.env("PORT", port.to_string());
+}
+
+/// Logs and counts the processes the kernel killed for running out of memory
+/// since `seen`, which is moved along.
+async fn report_oom(log: &DeploymentLog, phase: LogPhase, cgroup: Option<&Cgroup>, seen: &mut u64) -> Result<(), AppError> {
+    let Some(cgroup) = cgroup else { return Ok(()) };
+    let kills = cgroup.oom_kills();
+    if kills <= *seen {
+        return Ok(());
+    }
+    let new = kills - *seen;
+    *seen = kills;
+    log.conn.execute(
+        "UPDATE deployments SET oom_kills = oom_kills + ? WHERE id = ?",
+        (new as i64, log.deployment_id)
     ).await?;
+    log_error(log, phase, &format!("Out of memory: {} process(es) killed for going over the memory limit", new)).await
+}
+
+async fn update_logs(log: &DeploymentLog, phase: LogPhase, message: &str) -> Result<(), AppError> {
+    log.append(LogStream::System, phase, LogLevel::Info, message).await
+}
+
+async fn log_warning(log: &DeploymentLog, phase: LogPhase, message: &str) -> Result<(), AppError> {
+    log.append(LogStream::System, phase, LogLevel::Warn, message).await
+}
+
+async fn log_error(log: &DeploymentLog, phase: LogPhase, message: &str) -> Result<(), AppError> {
+    log.append(LogStream::System, phase, LogLevel::Error, message).await
+}
+
+/// Logs what a command printed, line by line.
+async fn log_output(log: &DeploymentLog, phase: LogPhase, stream: LogStream, output: &str) -> Result<(), AppError> {
+    log.append(stream, phase, LogLevel::Info, output).await
+}
 
+/// Moves a deployment along, unless it has been stopped in the meantime, and
+/// lets the webhooks know.
+async fn update_status(conn: &libsql::Connection, deployment_id: i64, status: i32) -> Result<(), AppError> {
+    let changed = conn.execute(
+        "UPDATE deployments SET status = ? WHERE id = ? AND status NOT IN (?, ?)",
+        (status, deployment_id, STATUS_STOPPED, status)
+    ).await?;
+    if changed > 0 {
+        if let Some((kind, message)) = EventKind::for_status(status) {
+            events::emit(conn, deployment_id, kind, message).await;
+        }
+    }
     Ok(())
 }
 
-pub async fn deploy(state: &AppState, proj_id: i32, deployment_id: i64) -> Result<(), AppError> {
+/// Turns the project's healthcheck_endpoint into a URL on this node.
+/// Full URLs are used as they are, `:3000/health` is resolved against
+/// localhost and `/health` against the deployment's port.
+fn healthcheck_url(endpoint: &str, port: u16) -> String {
+    if endpoint.starts_with("http://") || endpoint.starts_with("https://") {
+        endpoint.to_string()
+    } else if endpoint.starts_with(':') {
+        format!("http://127.0.0.1{}", endpoint)
+    } else if endpoint.starts_with('/') {
+        format!("http://127.0.0.1:{}{}", port, endpoint)
+    } else {
+        format!("http://{}", endpoint)
+    }
+}
+
+/// Polls the healthcheck URL until it answers 2xx, the timeout expires or the
+/// process exits. Every probe result is appended to the deployment logs.
+async fn wait_healthy(
+    log: &DeploymentLog,
+    url: &str,
+    timeout_ms: i32,
+    exited: &mut tokio::sync::oneshot::Receiver<()>,
+) -> Result<bool, AppError> {
+    let client = reqwest::Client::new();
+    let deadline = tokio::time::Instant::now() + tokio::time::Duration::from_millis(timeout_ms.max(0) as u64);
+    update_logs(log, LogPhase::Healthcheck, &format!("Waiting for {} to become healthy (timeout {}ms)", url, timeout_ms)).await?;
+
+    let mut attempt = 1;
+    loop {
+        let remaining = deadline.saturating_duration_since(tokio::time::Instant::now());
+        let probe = client.get(url).timeout(remaining.max(HEALTHCHECK_INTERVAL)).send();
+        let result = tokio::select! {
+            result = probe => result,
+            _ = &mut *exited => {
+                log_error(log, LogPhase::Healthcheck, "Healthcheck aborted: process exited").await?;
+                return Ok(false);
+            }
+        };
+
+        match result {
+            Ok(response) if response.status().is_success() => {
+                update_logs(log, LogPhase::Healthcheck, &format!("Healthcheck {}: {}", attempt, response.status())).await?;
+                return Ok(true);
+            }
+            Ok(response) => {
+                log_warning(log, LogPhase::Healthcheck, &format!("Healthcheck {}: {}", attempt, response.status())).await?;
+            }
+            Err(e) => {
+                // reqwest's own message is generic, the root cause is what's useful here
+                let e = e.without_url();
+                let mut reason = e.to_string();
+                let mut source = std::error::Error::source(&e);
+                while let Some(cause) = source {
+                    reason = cause.to_string();
+                    source = cause.source();
+                }
+                log_warning(log, LogPhase::Healthcheck, &format!("Healthcheck {}: {}", attempt, reason)).await?;
+            }
+        }
+
+        if tokio::time::Instant::now() + HEALTHCHECK_INTERVAL > deadline {
+            log_error(log, LogPhase::Healthcheck, &format!("Healthcheck timed out after {} attempts", attempt)).await?;
+            return Ok(false);
+        }
+        tokio::select! {
+            _ = tokio::time::sleep(HEALTHCHECK_INTERVAL) => {},
+            _ = &mut *exited => {
+                log_error(log, LogPhase::Healthcheck, "Healthcheck aborted: process exited").await?;
+                return Ok(false);
+            }
+        }
+        attempt += 1;
+    }
+}
+
+async fn current_status(conn: &libsql::Connection, deployment_id: i64) -> Option<i32> {
+    let mut rows = conn
+        .query("SELECT status FROM deployments WHERE id = ?", [deployment_id])
+        .await
+        .ok()?;
+    rows.next().await.ok()??.get(0).ok()
+}
+
+/// Starts a deployment's run command in its own process group. Its output
+/// goes to files in the deployment directory rather than pipes, so the process
+/// outlives the node and its output can be picked up again after a restart.
+fn spawn_run(
+    run_cmd: &str,
+    app_path: &str,
+    path: &str,
+    port: u16,
+    secrets: &[(String, String)],
+    cgroup: Option<&Cgroup>,
+    sandbox: Option<&Sandbox>,
+) -> std::io::Result<RunProcess> {
+    let stdout = output_file(path, LogStream::Stdout);
+    let stderr = output_file(path, LogStream::Stderr);
+    for file in [&stdout, &stderr] {
+        let _ = fs::remove_file(format!("{}.pos", file));
+    }
+    let mut command = tokio::process::Command::new("bash");
+    command
+        .arg("-c")
+        .arg(run_cmd)
+        .current_dir(app_path)
+        .stdout(open_output(&stdout)?)
+        .stderr(open_output(&stderr)?)
+        .process_group(0);
+    // Joined while still root, before the sandbox drops privileges
+    if let Some(cgroup) = cgroup {
+        cgroup.apply(&mut command)?;
+    }
+    if let Some(sandbox) = sandbox {
+        sandbox.apply(&mut command)?;
+    }
+    set_env(&mut command, port, secrets);
+    let run = command.spawn()?;
+
+    if let Some(pid) = run.id() {
+        let identity = process_identity(pid).unwrap_or_default();
+        fs::write(format!("{}/pid", path), format!("{}\n{}\n", pid, identity))?;
+    }
+    Ok(RunProcess::Child(run))
+}
+
+/// Tells a process apart from later ones that get its pid: the boot it was
+/// started in and its start time in clock ticks since then.
+fn process_identity(pid: u32) -> Option<String> {
+    let boot_id = fs::read_to_string(BOOT_ID_FILE).ok()?;
+    let stat = fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+    // The command name in parentheses may hold spaces, the fields after it don't
+    let start_time = stat.rsplit_once(')')?.1.split_whitespace().nth(19)?;
+    Some(format!("{} {}", boot_id.trim(), start_time))
+}
+
+/// The process group of the run command started in deployment directory
+/// `path`, if that group is still around. A pid recorded before a reboot, or
+/// one that has since been reused by another process, doesn't count.
+pub fn running_pgid(path: &str) -> Option<i32> {
+    let content = fs::read_to_string(format!("{}/pid", path)).ok()?;
+    let mut lines = content.lines();
+    let pgid = lines.next()?.trim().parse::<i32>().ok()?;
+    if pgid <= 0 || killpg(Pid::from_raw(pgid), None) == Err(Errno::ESRCH) {
+        return None;
+    }
+    match lines.next().map(str::trim).filter(|identity| !identity.is_empty()) {
+        Some(identity) => {
+            let (boot_id, _) = identity.split_once(' ')?;
+            if fs::read_to_string(BOOT_ID_FILE).ok()?.trim() != boot_id {
+                return None;
+            }
+            // A group's id isn't handed out again while it exists, so a leader with
+            // that pid and another start time means ours is gone. Without a leader,
+            // what is left of the group is still ours.
+            match process_identity(pgid as u32) {
+                Some(leader) => (leader == identity).then_some(pgid),
+                None => Some(pgid),
+            }
+        }
+        // Written before identities were recorded, the process has to be working in this project
+        None => {
+            let cwd = fs::read_link(format!("/proc/{}/cwd", pgid)).ok()?;
+            let project_dir = fs::canonicalize(std::path::Path::new(path).parent()?).ok()?;
+            cwd.starts_with(project_dir).then_some(pgid)
+        }
+    }
+}
+
+/// Empties an output file and opens it for the run process to write to. It
+/// writes in append mode, so that when `OutputTail` empties the file the next
+/// line lands at its start again instead of after a hole of NUL bytes.
+fn open_output(file: &str) -> std::io::Result<fs::File> {
+    fs::File::create(file)?;
+    fs::OpenOptions::new().append(true).open(file)
+}
+
+fn output_file(path: &str, stream: LogStream) -> String {
+    match stream {
+        LogStream::Stderr => format!("{}/run.stderr", path),
+        _ => format!("{}/run.stdout", path),
+    }
+}
+
+/// A deployment's run process: either a child of this node, or one that an
+/// earlier node process started and this one adopted on startup.
+enum RunProcess {
+    Child(tokio::process::Child),
+    Adopted(i32),
+}
+
+impl RunProcess {
+    async fn wait(&mut self) -> std::io::Result<std::process::ExitStatus> {
+        match self {
+            RunProcess::Child(child) => child.wait().await,
+            RunProcess::Adopted(pgid) => {
+                let pid = Pid::from_raw(*pgid);
+                loop {
+                    // After the node re-executed itself for an update its processes are still its
+                    // children. Otherwise all we can do is watch for the process group to go away.
+                    match waitpid(pid, Some(WaitPidFlag::WNOHANG)) {
+                        Ok(WaitStatus::Exited(_, code)) => return Ok(std::process::ExitStatus::from_raw(code << 8)),
+                        Ok(WaitStatus::Signaled(_, signal, _)) => return Ok(std::process::ExitStatus::from_raw(signal as i32)),
+                        Ok(_) => {}
+                        Err(_) if killpg(pid, None) == Err(Errno::ESRCH) => {
+                            return Err(std::io::Error::other("exit status unknown, the process was started before the node restarted"));
+                        }
+                        Err(_) => {}
+                    }
+                    tokio::time::sleep(ADOPTED_POLL_INTERVAL).await;
+                }
+            }
+        }
+    }
+}
+
+/// Follows one of the run process's output files, logging complete lines and
+/// remembering how far it got in a `.pos` file next to it.
+struct OutputTail {
+    stream: LogStream,
+    file: String,
+    offset: u64,
+    partial: Vec<u8>,
+}
+
+impl OutputTail {
+    fn new(path: &str, stream: LogStream) -> Self {
+        let file = output_file(path, stream);
+        let offset = fs::read_to_string(format!("{}.pos", file))
+            .ok()
+            .and_then(|pos| pos.trim().parse().ok())
+            .unwrap_or(0);
+        OutputTail { stream, file, offset, partial: Vec::new() }
+    }
+
+    async fn drain(&mut self, log: &DeploymentLog) -> std::io::Result<()> {
+        let mut file = fs::File::open(&self.file)?;
+        let len = file.metadata()?.len();
+        if len < self.offset {
+            self.offset = 0;
+        }
+        if len == self.offset {
+            return Ok(());
+        }
+        file.seek(SeekFrom::Start(self.offset))?;
+        let read = file.take(OUTPUT_READ_SIZE).read_to_end(&mut self.partial)?;
+        self.offset += read as u64;
+        if let Some(end) = self.partial.iter().rposition(|&b| b == b'\n') {
+            let lines: Vec<u8> = self.partial.drain(..=end).collect();
+            let _ = log_output(log, LogPhase::Run, self.stream, &String::from_utf8_lossy(&lines)).await;
+        }
+
+        // Empty the file once everything in it is logged, so it doesn't grow
+        // forever. Anything written between the check and the truncate is lost.
+        if self.offset >= OUTPUT_TRUNCATE_SIZE && self.partial.is_empty() && fs::metadata(&self.file)?.len() == self.offset {
+            fs::OpenOptions::new().write(true).open(&self.file)?.set_len(0)?;
+            self.offset = 0;
+        }
+        let pos = self.offset - self.partial.len() as u64;
+        fs::write(format!("{}.pos", self.file), pos.to_string())
+    }
+
+    async fn flush(&mut self, log: &DeploymentLog) {
+        let _ = self.drain(log).await;
+        if !self.partial.is_empty() {
+            let line = String::from_utf8_lossy(&self.partial).to_string();
+            let _ = log_output(log, LogPhase::Run, self.stream, &line).await;
+            self.partial.clear();
+        }
+    }
+}
+
+/// Copies the process output into the deployment logs until it exits.
+async fn capture_output(
+    log: &DeploymentLog,
+    path: &str,
+    run: &mut RunProcess,
+) -> std::io::Result<std::process::ExitStatus> {
+    let mut tails = [OutputTail::new(path, LogStream::Stdout), OutputTail::new(path, LogStream::Stderr)];
+    let mut interval = tokio::time::interval(OUTPUT_POLL_INTERVAL);
+    let wait = run.wait();
+    tokio::pin!(wait);
+
+    let result = loop {
+        tokio::select! {
+            result = &mut wait => break result,
+            _ = interval.tick() => {
+                for tail in &mut tails {
+                    let _ = tail.drain(log).await;
+                }
+            }
+        }
+    };
+    for tail in &mut tails {
+        tail.flush(log).await;
+    }
+    result
+}
+
+async fn record_restart(
+    conn: &libsql::Connection,
+    deployment_id: i64,
+    exit: &std::io::Result<std::process::ExitStatus>,
+) -> Result<(), AppError> {
+    let (exit_code, signal) = match exit {
+        Ok(status) => (status.code(), status.signal()),
+        Err(_) => (None, None),
+    };
+    conn.execute(
+        "INSERT INTO deployment_restarts (deployment_id, exit_code, signal) VALUES (?, ?, ?)",
+        (deployment_id, exit_code, signal)
+    ).await?;
+    conn.execute(
+        "UPDATE deployments SET restart_count = restart_count + 1 WHERE id = ?",
+        [deployment_id]
+    ).await?;
+    Ok(())
+}
+
+/// How long to wait before the next restart after `consecutive` restarts in
+/// a row: doubling from one second, but never more than a minute.
+fn restart_delay(consecutive: u32) -> tokio::time::Duration {
+    RESTART_BACKOFF_BASE.saturating_mul(1 << consecutive.min(16)).min(RESTART_BACKOFF_MAX)
+}
+
+/// What the supervisor needs to (re)start a deployment's run process.
+struct RunSpec {
+    run_cmd: String,
+    app_path: String,
+    path: String,
+    policy: RestartPolicy,
+    max_restarts: i32,
+    secrets: Vec<(String, String)>,
+    cgroup: Option<Cgroup>,
+    sandbox: Option<Sandbox>,
+    routes: Routes,
+}
+
+/// Owns a deployment's run process: captures its output and restarts it
+/// according to the project's restart policy until it is stopped, fails to
+/// start, or runs out of restarts and is marked as crash looping.
+async fn supervise(
+    log: DeploymentLog,
+    spec: RunSpec,
+    mut run: RunProcess,
+    exited_tx: tokio::sync::oneshot::Sender<()>,
+) {
+    let RunSpec { run_cmd, app_path, path, policy, max_restarts, secrets, cgroup, sandbox, routes } = spec;
+    let DeploymentLog { ref conn, deployment_id, .. } = log;
+    let pid_file = format!("{}/pid", path);
+    let mut exited_tx = Some(exited_tx);
+    let mut consecutive: u32 = 0;
+    let mut oom_kills = cgroup.as_ref().map_or(0, Cgroup::oom_kills);
+
+    loop {
+        let started = tokio::time::Instant::now();
+        let result = capture_output(&log, &path, &mut run).await;
+
+        let _ = fs::remove_file(&pid_file);
+        let _ = match &result {
+            Ok(status) if status.success() => update_logs(&log, LogPhase::Run, &format!("Process exited with status: {}", status)).await,
+            Ok(status) => log_warning(&log, LogPhase::Run, &format!("Process exited with status: {}", status)).await,
+            Err(e) => log_error(&log, LogPhase::Run, &format!("Process error: {}", e)).await,
+        };
+        let _ = report_oom(&log, LogPhase::Run, cgroup.as_ref(), &mut oom_kills).await;
+        if let Some(tx) = exited_tx.take() {
+            let _ = tx.send(());
+        }
+
+        // Exiting before the healthcheck passed means the deployment never started
+        let failed_to_start = conn.execute(
+            "UPDATE deployments SET status = ? WHERE id = ? AND status = ?",
+            (STATUS_FAILED, deployment_id, STATUS_STARTING)
+        ).await;
+        if matches!(failed_to_start, Ok(n) if n > 0) {
+            events::emit(conn, deployment_id, EventKind::Failed, "The process exited before the deployment became healthy").await;
+            break;
+        }
+        // Anything but running means it was stopped or failed on purpose
+        if current_status(conn, deployment_id).await != Some(STATUS_RUNNING) {
+            break;
+        }
+
+        let succeeded = matches!(&result, Ok(status) if status.success());
+        if !succeeded {
+            let message = match &result {
+                Ok(status) => format!("Process exited with status: {}", status),
+                Err(e) => format!("Process error: {}", e),
+            };
+            events::emit(conn, deployment_id, EventKind::Crashed, &message).await;
+        }
+        let restart = match policy {
+            RestartPolicy::Never => false,
+            RestartPolicy::OnFailure => !succeeded,
+            RestartPolicy::Always => true,
+        };
+        if !restart {
+            let _ = update_status(conn, deployment_id, STATUS_STOPPED).await;
+            break;
+        }
+
+        if started.elapsed() >= STABLE_RUN {
+            consecutive = 0;
+        }
+        if consecutive >= max_restarts.max(0) as u32 {
+            let _ = update_status(conn, deployment_id, STATUS_CRASHLOOP).await;
+            let _ = log_error(&log, LogPhase::Run, &format!("Giving up after {} restarts, deployment is crash looping", consecutive)).await;
+            break;
+        }
+
+        let delay = restart_delay(consecutive);
+        consecutive += 1;
+        let _ = record_restart(conn, deployment_id, &result).await;
+        let _ = update_logs(&log, LogPhase::Run, &format!("Restarting in {}s (attempt {}/{})", delay.as_secs(), consecutive, max_restarts)).await;
+        tokio::time::sleep(delay).await;
+
+        if current_status(conn, deployment_id).await != Some(STATUS_RUNNING) {
+            break;
+        }
+        let spawned = match ports::assign(conn, deployment_id).await {
+            Ok(port) => spawn_run(&run_cmd, &app_path, &path, port, &secrets, cgroup.as_ref(), sandbox.as_ref()),
+            Err(e) => Err(std::io::Error::other(format!("{:?}", e))),
+        };
+        match spawned {
+            Ok(child) => {
+                let _ = update_logs(&log, LogPhase::Run, &format!("Restarted service with: {}", run_cmd)).await;
+                let message = format!("Restarted (attempt {}/{})", consecutive, max_restarts);
+                events::emit(conn, deployment_id, EventKind::Restarted, &message).await;
+                // Its old port may have been taken in the meantime, so the proxy has to look again
+                routes.refresh(conn).await;
+                run = child;
+            }
+            Err(e) => {
+                let _ = update_status(conn, deployment_id, STATUS_FAILED).await;
+                let _ = log_error(&log, LogPhase::Run, &format!("Failed to restart: {}", e)).await;
+                break;
+            }
+        }
+    }
+    cgroup::remove(deployment_id);
+}
+
+/// Starts a stopped project again from the newest deployment whose build is
+/// still on disk, without fetching or building anything. A project that is
+/// already up is left alone. Returns the deployment that is (now) running.
+pub async fn start_project(state: &AppState, proj_id: i32) -> Result<i64, AppError> {
     let conn = state.db.connect()?;
-    conn.query("PRAGMA busy_timeout = 10000", ()).await?; 
-    stop_deployment_with_conn(&conn, proj_id).await?; 
+    conn.query("PRAGMA busy_timeout = 10000", ()).await?;
+    let project_name = {
+        let mut rows = conn.query("SELECT name FROM projects WHERE id = ?", [proj_id]).await?;
+        let row = rows.next().await?.ok_or(AppError::NotFound)?;
+        row.get::<String>(0)?
+    };
+    let deployments = {
+        let mut rows = conn
+            .query("SELECT id, status, build_id FROM deployments WHERE project_id = ? ORDER BY id DESC", [proj_id])
+            .await?;
+        let mut deployments = Vec::new();
+        while let Some(row) = rows.next().await? {
+            deployments.push((row.get::<i64>(0)?, row.get::<i32>(1)?, row.get::<Option<i64>>(2)?));
+        }
+        deployments
+    };
+
+    conn.execute("UPDATE projects SET stopped = 0 WHERE id = ?", [proj_id]).await?;
+    if let Some((deployment_id, _, _)) = deployments
+        .iter()
+        .find(|(_, status, _)| !matches!(*status, STATUS_STOPPED | STATUS_FAILED | STATUS_CRASHLOOP))
+    {
+        return Ok(*deployment_id);
+    }
+    let (deployment_id, _, _) = deployments
+        .into_iter()
+        .find(|(id, _, build_id)| finished_build(&project_name, build_id.unwrap_or(*id)).is_some())
+        .ok_or(AppError::BadRequest)?;
 
     conn.execute(
-        "UPDATE deployments SET status = ? WHERE project_id = ? AND status = ?",
-        (STATUS_STOPPED, proj_id, STATUS_RUNNING)
+        "UPDATE deployments SET status = ? WHERE id = ?",
+        (STATUS_PENDING, deployment_id)
     ).await?;
-    
-    async fn update_logs(conn: &libsql::Connection, deployment_id: i64, new_logs: &str) -> Result<(), AppError> {
-        conn.execute(
-            "UPDATE deployments SET logs = logs || ? WHERE id = ?",
-            (new_logs, deployment_id)
-        ).await?;
-        Ok(())
+    let state = state.clone();
+    tokio::spawn(async move {
+        if let Err(e) = relaunch(&state, proj_id, deployment_id).await {
+            eprintln!("Start error: {:?}", e);
+        }
+    });
+    Ok(deployment_id)
+}
+
+/// Fails a deployment that was still being set up when the node went down.
+/// Nothing carries on with it after a restart, so it would otherwise be left
+/// in progress for good, holding on to its port. A run process it already
+/// started is stopped.
+pub async fn fail_interrupted(state: &AppState, deployment_id: i64) -> Result<(), AppError> {
+    let conn = state.db.connect()?;
+    conn.query("PRAGMA busy_timeout = 10000", ()).await?;
+    let (project_name, stop_timeout) = {
+        let mut rows = conn
+            .query(
+                "SELECT p.name, p.stop_timeout FROM deployments d JOIN projects p ON p.id = d.project_id WHERE d.id = ?",
+                [deployment_id],
+            )
+            .await?;
+        let row = rows.next().await?.ok_or(AppError::NotFound)?;
+        (row.get::<String>(0)?, row.get::<Option<i32>>(1)?.unwrap_or(DEFAULT_STOP_TIMEOUT))
+    };
+    let log = DeploymentLog::new(&conn, &state.logs, &project_name, deployment_id);
+    update_status(&conn, deployment_id, STATUS_FAILED).await?;
+    log_error(&log, LogPhase::Run, "The node restarted before the deployment was finished").await?;
+
+    let path = format!("projects/{}/{}", project_name, deployment_id);
+    if let Some(pgid) = running_pgid(&path) {
+        kill_process_group(&log, pgid, stop_timeout).await?;
     }
+    let _ = fs::remove_file(format!("{}/pid", path));
+    Ok(())
+}
 
-    async fn update_status(conn: &libsql::Connection, deployment_id: i64, status: i32) -> Result<(), AppError> {
-        conn.execute(
-            "UPDATE deployments SET status = ? WHERE id = ?",
-            (status, deployment_id)
-        ).await?;
-        Ok(())
+/// Picks a deployment that was running when the node went down back up. A
+/// process that is still alive is adopted and its output followed again, a
+/// dead one is started again from its build. Only when the build is gone does
+/// the project get deployed from scratch.
+pub async fn resume_deployment(state: &AppState, proj_id: i32, deployment_id: i64) -> Result<(), AppError> {
+    let conn = state.db.connect()?;
+    conn.query("PRAGMA busy_timeout = 10000", ()).await?;
+    let project = {
+        let mut rows = conn
+            .query(&format!("SELECT {} FROM projects WHERE id = ?", Project::COLUMNS), [proj_id])
+            .await?;
+        let row = rows.next().await?.ok_or(AppError::NotFound)?;
+        Project::from_row(&row)?
+    };
+    let build_id = {
+        let mut rows = conn.query("SELECT build_id FROM deployments WHERE id = ?", [deployment_id]).await?;
+        let row = rows.next().await?.ok_or(AppError::NotFound)?;
+        row.get::<Option<i64>>(0)?.unwrap_or(deployment_id)
+    };
+    let log = DeploymentLog::new(&conn, &state.logs, &project.name, deployment_id);
+    let path = format!("projects/{}/{}", project.name, deployment_id);
+    let app_path = format!("projects/{}/{}/app", project.name, build_id);
+
+    if finished_build(&project.name, build_id).is_none() {
+        log_warning(&log, LogPhase::Run, "The build is no longer on disk, deploying the project again").await?;
+        update_status(&conn, deployment_id, STATUS_STOPPED).await?;
+        let git_ref = project.git_ref.clone().filter(|r| !r.is_empty());
+        super::start_deployment(state, proj_id, git_ref, None, "Redeploying after node restart...\n").await?;
+        return Ok(());
     }
 
-    let mut rows = conn
-        .query(
-            "SELECT id, name, git_repo, install_cmd, build_cmd, run_cmd, env, healthcheck_endpoint, healthcheck_timeout 
-             FROM projects WHERE id = ?",
-            [proj_id]
-        )
-        .await?;
+    let Some(pgid) = running_pgid(&path) else {
+        log_warning(&log, LogPhase::Run, "The process is gone after a node restart").await?;
+        return relaunch(state, proj_id, deployment_id).await;
+    };
 
-    let row = rows.next().await?.ok_or(AppError::NotFound)?;
-    
-    let project = Project {
-        id: Some(row.get(0)?),
-        name: row.get(1)?,
-        git_repo: row.get(2)?,
-        install_cmd: row.get(3)?,
-        build_cmd: row.get(4)?,
-        run_cmd: row.get(5)?,
-        env: row.get(6)?,
-        healthcheck_endpoint: row.get(7)?,
-        healthcheck_timeout: row.get(8)?,
+    update_logs(&log, LogPhase::Run, &format!("Process group {} is still running after a node restart, following it again", pgid)).await?;
+    // The process is still in the cgroup it was started in, only the limits may have changed
+    let cgroup = cgroup(&log, &project, LogPhase::Run).await?;
+    let sandbox = sandbox(&log, &project, &app_path, LogPhase::Run).await?;
+    let secrets = secrets(&log, &project, LogPhase::Run).await?;
+    let spec = RunSpec {
+        run_cmd: project.run_cmd.unwrap_or_default(),
+        app_path,
+        path,
+        policy: RestartPolicy::parse(project.restart_policy.as_deref())?,
+        max_restarts: project.max_restarts.unwrap_or(DEFAULT_MAX_RESTARTS),
+        secrets,
+        cgroup,
+        sandbox,
+        routes: state.routes.clone(),
+    };
+    let (exited_tx, _) = tokio::sync::oneshot::channel();
+    tokio::spawn(supervise(log, spec, RunProcess::Adopted(pgid), exited_tx));
+    Ok(())
+}
+
+/// Runs an already built deployment again in its own directory.
+async fn relaunch(state: &AppState, proj_id: i32, deployment_id: i64) -> Result<(), AppError> {
+    let conn = state.db.connect()?;
+    conn.query("PRAGMA busy_timeout = 10000", ()).await?;
+    let project = {
+        let mut rows = conn
+            .query(&format!("SELECT {} FROM projects WHERE id = ?", Project::COLUMNS), [proj_id])
+            .await?;
+        let row = rows.next().await?.ok_or(AppError::NotFound)?;
+        Project::from_row(&row)?
+    };
+    let build_id = {
+        let mut rows = conn.query("SELECT build_id FROM deployments WHERE id = ?", [deployment_id]).await?;
+        let row = rows.next().await?.ok_or(AppError::NotFound)?;
+        row.get::<Option<i64>>(0)?.unwrap_or(deployment_id)
     };
+    let log = DeploymentLog::new(&conn, &state.logs, &project.name, deployment_id);
+    update_logs(&log, LogPhase::Run, "Starting deployment again").await?;
+    events::emit(&conn, deployment_id, EventKind::DeployStarted, "Starting the existing build again").await;
 
     let path = format!("projects/{}/{}", project.name, deployment_id);
-    let log_msg = format!("Cloning {} into {}\n", project.git_repo, path);
-    update_logs(&conn, deployment_id, &log_msg).await?;
-    
-    fs::create_dir_all(&path)?;
+    let app_path = format!("projects/{}/{}/app", project.name, build_id);
+    write_env(&log, &project, &app_path).await?;
+    launch(state, log, proj_id, project, path, app_path).await
+}
 
-    let output = tokio::process::Command::new("git")
-        .arg("clone")
-        .arg(&project.git_repo)
-        .arg(&path)
-        .output()
-        .await?;
+pub async fn deploy(state: &AppState, proj_id: i32, deployment_id: i64) -> Result<(), AppError> {
+    let conn = state.db.connect()?;
+    conn.query("PRAGMA busy_timeout = 10000", ()).await?; 
 
-    update_logs(&conn, deployment_id, &String::from_utf8_lossy(&output.stdout)).await?;
-    if output.status.success() {
-        let rev_output = tokio::process::Command::new("git")
-            .arg("rev-parse")
-            .arg("HEAD")
-            .current_dir(&path)
-            .output()
+    // Rows keep their statement, and with it a read transaction, open until
+    // dropped. Any write on this connection would fail with SQLITE_BUSY_SNAPSHOT
+    // once another connection committed in the meantime.
+    let project = {
+        let mut rows = conn
+            .query(
+                &format!("SELECT {} FROM projects WHERE id = ?", Project::COLUMNS),
+                [proj_id]
+            )
             .await?;
-        let commit_hash = String::from_utf8_lossy(&rev_output.stdout).trim().to_string();
-        conn.execute(
-            "UPDATE deployments SET commit_hash = ? WHERE id = ?",
-            (commit_hash, deployment_id)
-        ).await?;
-    } else {
-        update_status(&conn, deployment_id, STATUS_FAILED).await?;
-        let error = String::from_utf8_lossy(&output.stderr).to_string();
-        update_logs(&conn, deployment_id, &format!("Error: {}\n", error)).await?;
-        return Err(AppError::Internal(error));
+
+        let row = rows.next().await?.ok_or(AppError::NotFound)?;
+        Project::from_row(&row)?
+    };
+    let log = DeploymentLog::new(&conn, &state.logs, &project.name, deployment_id);
+
+    let (git_ref, build_id) = {
+        let mut rows = conn
+            .query("SELECT git_ref, build_id FROM deployments WHERE id = ?", [deployment_id])
+            .await?;
+        let row = rows.next().await?.ok_or(AppError::NotFound)?;
+        (row.get::<Option<String>>(0)?, row.get::<Option<i64>>(1)?)
+    };
+    let git_ref = git_ref.or(project.git_ref.clone()).filter(|r| !r.is_empty());
+    let build_id = build_id.filter(|&id| id != deployment_id);
+    let message = format!("Deploying {}", git_ref.as_deref().unwrap_or("the default branch"));
+    events::emit(&conn, deployment_id, EventKind::DeployStarted, &message).await;
+
+    // Logs and the pid file live next to the checkout, in the deployment directory
+    let path = format!("projects/{}/{}", project.name, deployment_id);
+    fs::create_dir_all(&path)?;
+    let port = port(&log, LogPhase::Clone).await?;
+    update_logs(&log, LogPhase::Clone, &format!("Assigned port {}", port)).await?;
+
+    let reused = build_id.and_then(|id| Some((id, finished_build(&project.name, id)?)));
+    let app_path = match reused {
+        Some((build_id, commit_hash)) => {
+            update_logs(&log, LogPhase::Clone, &format!("Reusing the build of deployment {} at commit {}", build_id, commit_hash)).await?;
+            conn.execute(
+                "UPDATE deployments SET commit_hash = ? WHERE id = ?",
+                (commit_hash, deployment_id)
+            ).await?;
+            let app_path = format!("projects/{}/{}/app", project.name, build_id);
+            // The environment is configuration rather than part of the build, so it is always the current one
+            write_env(&log, &project, &app_path).await?;
+            app_path
+        }
+        None => {
+            if let Some(build_id) = build_id {
+                log_warning(&log, LogPhase::Clone, &format!("The build of deployment {} is no longer on disk, rebuilding", build_id)).await?;
+                conn.execute("UPDATE deployments SET build_id = NULL WHERE id = ?", [deployment_id]).await?;
+            }
+            build(&log, &project, &path, port, git_ref).await?;
+            format!("{}/app", path)
+        }
+    };
+
+    launch(state, log, proj_id, project, path, app_path).await
+}
+
+async fn write_env(log: &DeploymentLog, project: &Project, app_path: &str) -> Result<(), AppError> {
+    if let Some(env_vars) = project.env.as_ref().filter(|vars| !vars.is_empty()) {
+        write_owned_file(app_path, ".env", &env_vars::render(env_vars))?;
+        update_logs(log, LogPhase::Clone, "Created .env file").await?;
     }
+    Ok(())
+}
 
-    if let Some(env_vars) = project.env {
-        let env_path = format!("{}/{}", path, ".env");
-        fs::write(&env_path, env_vars)?;
-        update_logs(&conn, deployment_id, "Created .env file\n").await?;
+/// Writes `name` in `dir` readable only by whoever owns `dir`, which is the
+/// project's own user when it is isolated. That user may have left anything
+/// at the path, a symlink included, so it is replaced rather than written
+/// through.
+fn write_owned_file(dir: &str, name: &str, contents: &str) -> std::io::Result<()> {
+    let path = format!("{}/{}", dir, name);
+    match fs::remove_file(&path) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e),
+        _ => {}
     }
+    let mut file = fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .custom_flags(OFlag::O_NOFOLLOW.bits())
+        .open(&path)?;
+    file.write_all(contents.as_bytes())?;
+    let owner = fs::metadata(dir)?;
+    std::os::unix::fs::fchown(&file, Some(owner.uid()), Some(owner.gid()))?;
+    file.set_permissions(fs::Permissions::from_mode(0o600))
+}
+
+/// The commit a deployment's build directory holds, if it was built
+/// completely and is still on disk.
+fn finished_build(project_name: &str, deployment_id: i64) -> Option<String> {
+    let path = format!("projects/{}/{}", project_name, deployment_id);
+    fs::metadata(format!("{}/app", path)).ok()?;
+    let commit_hash = fs::read_to_string(format!("{}/{}", path, BUILT_MARKER)).ok()?;
+    Some(commit_hash.trim().to_string()).filter(|c| !c.is_empty())
+}
+
+/// Runs git with `args`, logging its output to the clone phase, and returns
+/// what it printed. A failing git command fails the deployment.
+async fn git(log: &DeploymentLog, args: &[&str]) -> Result<String, AppError> {
+    let output = tokio::process::Command::new("git").args(args).output().await?;
+
+    log_output(log, LogPhase::Clone, LogStream::Stdout, &String::from_utf8_lossy(&output.stdout)).await?;
+    log_output(log, LogPhase::Clone, LogStream::Stderr, &String::from_utf8_lossy(&output.stderr)).await?;
+    if !output.status.success() {
+        update_status(&log.conn, log.deployment_id, STATUS_FAILED).await?;
+        log_error(log, LogPhase::Clone, &format!("git {} failed with {}", args.join(" "), output.status)).await?;
+        return Err(AppError::Internal(String::from_utf8_lossy(&output.stderr).to_string()));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+/// Runs git with `args` without logging anything, for lookups that may fail.
+async fn git_quiet(args: &[&str]) -> Option<String> {
+    let output = tokio::process::Command::new("git").args(args).output().await.ok()?;
+    output
+        .status
+        .success()
+        .then(|| String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+/// Brings the project's bare mirror up to date with its repository, creating
+/// it on first use or when the repository URL changed.
+async fn update_mirror(log: &DeploymentLog, project: &Project, mirror: &str, depth: &[&str]) -> Result<(), AppError> {
+    let url = git_quiet(&["--git-dir", mirror, "config", "--get", "remote.origin.url"]).await;
+    if url.as_deref() == Some(project.git_repo.as_str()) {
+        update_logs(log, LogPhase::Clone, &format!("Fetching {} into {}", project.git_repo, mirror)).await?;
+        let mut args = vec!["--git-dir", mirror, "fetch", "--prune"];
+        args.extend_from_slice(depth);
+        args.extend_from_slice(&["origin", "+refs/heads/*:refs/heads/*", "+refs/tags/*:refs/tags/*"]);
+        git(log, &args).await?;
+        return Ok(());
+    }
+
+    if fs::metadata(mirror).is_ok() {
+        update_logs(log, LogPhase::Clone, "The repository changed, recreating the mirror").await?;
+        fs::remove_dir_all(mirror)?;
+    }
+    update_logs(log, LogPhase::Clone, &format!("Cloning {} into {}", project.git_repo, mirror)).await?;
+    let mut args = vec!["clone", "--bare"];
+    args.extend_from_slice(depth);
+    args.extend_from_slice(&["--", &project.git_repo, mirror]);
+    git(log, &args).await?;
+    Ok(())
+}
+
+/// Updates the project's mirror and checks `git_ref` (the default branch if
+/// there is none) out of it into a worktree at `path/app`, returning the
+/// commit it resolved to.
+async fn checkout(log: &DeploymentLog, project: &Project, path: &str, git_ref: Option<&str>) -> Result<String, AppError> {
+    let mirror = format!("projects/{}/{}", project.name, MIRROR_DIR);
+    let app_path = format!("{}/app", path);
+    let depth = project
+        .clone_depth
+        .filter(|&depth| depth > 0)
+        .map(|depth| depth.to_string());
+    let depth: Vec<&str> = match depth.as_deref() {
+        Some(depth) => vec!["--depth", depth],
+        None => Vec::new(),
+    };
+
+    // Deployments of the same project share the mirror, so only one gets to touch it at a time
+    let mirror_lock = MIRROR_LOCKS.lock().unwrap().entry(mirror.clone()).or_default().clone();
+    let mirror_guard = mirror_lock.lock().await;
+    update_mirror(log, project, &mirror, &depth).await?;
+
+    let target = git_ref.unwrap_or("HEAD");
+    let resolved = git_quiet(&["--git-dir", &mirror, "rev-parse", "--verify", &format!("{}^{{commit}}", target)]).await;
+    let commit_hash = match resolved {
+        Some(commit_hash) => commit_hash,
+        None => {
+            // Not a branch or tag, possibly a commit beyond the shallow history
+            update_logs(log, LogPhase::Clone, &format!("Fetching {}", target)).await?;
+            let mut args = vec!["--git-dir", mirror.as_str(), "fetch"];
+            args.extend_from_slice(&depth);
+            args.extend_from_slice(&["origin", target]);
+            git(log, &args).await?;
+            git(log, &["--git-dir", &mirror, "rev-parse", "--verify", "FETCH_HEAD^{commit}"]).await?
+        }
+    };
+
+    // A restarted deployment gets a fresh checkout
+    let _ = fs::remove_file(format!("{}/{}", path, BUILT_MARKER));
+    if fs::metadata(&app_path).is_ok() {
+        fs::remove_dir_all(&app_path)?;
+    }
+    git_quiet(&["--git-dir", &mirror, "worktree", "prune"]).await;
+    update_logs(log, LogPhase::Clone, &format!("Checking out {} into {}", target, app_path)).await?;
+    git(log, &["--git-dir", &mirror, "worktree", "add", "--force", "--detach", &app_path, &commit_hash]).await?;
+    drop(mirror_guard);
+
+    if project.submodules == Some(true) {
+        update_logs(log, LogPhase::Clone, "Updating submodules").await?;
+        let mut args = vec!["-C", app_path.as_str(), "submodule", "update", "--init", "--recursive"];
+        args.extend_from_slice(&depth);
+        git(log, &args).await?;
+    }
+
+    Ok(commit_hash)
+}
+
+/// Checks out `git_ref` into the deployment directory and runs the install
+/// and build commands there. Marks the build as finished when they all pass.
+async fn build(log: &DeploymentLog, project: &Project, path: &str, port: u16, git_ref: Option<String>) -> Result<(), AppError> {
+    let conn = &log.conn;
+    let deployment_id = log.deployment_id;
+    let app_path = format!("{}/app", path);
+    let commit_hash = checkout(log, project, path, git_ref.as_deref()).await?;
+    update_logs(log, LogPhase::Clone, &format!("Deploying commit {}", commit_hash)).await?;
+    conn.execute(
+        "UPDATE deployments SET git_ref = ?, commit_hash = ? WHERE id = ?",
+        (git_ref, commit_hash.clone(), deployment_id)
+    ).await?;
+    let sandbox = sandbox(log, project, &app_path, LogPhase::Install).await?;
+    let cgroup = cgroup(log, project, LogPhase::Install).await?;
+    let mut oom_kills = cgroup.as_ref().map_or(0, Cgroup::oom_kills);
+    let secrets = secrets(log, project, LogPhase::Install).await?;
+    write_env(log, project, &app_path).await?;
 
     if let Some(cmd) = project.install_cmd.as_deref() {
-        update_status(&conn, deployment_id, STATUS_INSTALLING).await?;
-        update_logs(&conn, deployment_id, &format!("Running install command: {}\n", cmd)).await?;
-        let install = tokio::process::Command::new("bash")
-            .arg("-c")
-            .arg(cmd)
-            .current_dir(&path)
-            .output()
-            .await?;
+        update_status(conn, deployment_id, STATUS_INSTALLING).await?;
+        update_logs(log, LogPhase::Install, &format!("Running install command: {}", cmd)).await?;
+        let mut command = tokio::process::Command::new("bash");
+        command.arg("-c").arg(cmd).current_dir(&app_path);
+        if let Some(cgroup) = &cgroup {
+            cgroup.apply(&mut command)?;
+        }
+        if let Some(sandbox) = &sandbox {
+            sandbox.apply(&mut command)?;
+        }
+        set_env(&mut command, port, &secrets);
+        let install = command.output().await?;
 
-        update_logs(&conn, deployment_id, &String::from_utf8_lossy(&install.stdout)).await?;
+        log_output(log, LogPhase::Install, LogStream::Stdout, &String::from_utf8_lossy(&install.stdout)).await?;
+        log_output(log, LogPhase::Install, LogStream::Stderr, &String::from_utf8_lossy(&install.stderr)).await?;
+        report_oom(log, LogPhase::Install, cgroup.as_ref(), &mut oom_kills).await?;
         if !install.status.success() {
-            update_status(&conn, deployment_id, STATUS_FAILED).await?;
-            let error = String::from_utf8_lossy(&install.stderr).to_string();
-            update_logs(&conn, deployment_id, &format!("Error: {}\n", error)).await?;
-            return Err(AppError::Internal(error));
+            cgroup::remove(deployment_id);
+            update_status(conn, deployment_id, STATUS_FAILED).await?;
+            log_error(log, LogPhase::Install, &format!("install command failed with {}", install.status)).await?;
+            return Err(AppError::Internal(String::from_utf8_lossy(&install.stderr).to_string()));
         }
     }
 
     if let Some(cmd) = project.build_cmd.as_deref() {
-        update_status(&conn, deployment_id, STATUS_BUILDING).await?;
-        update_logs(&conn, deployment_id, &format!("Running build command: {}\n", cmd)).await?;
-        let build = tokio::process::Command::new("bash")
-            .arg("-c")
-            .arg(cmd)
-            .current_dir(&path)
-            .output()
-            .await?;
+        update_status(conn, deployment_id, STATUS_BUILDING).await?;
+        update_logs(log, LogPhase::Build, &format!("Running build command: {}", cmd)).await?;
+        let mut command = tokio::process::Command::new("bash");
+        command.arg("-c").arg(cmd).current_dir(&app_path);
+        if let Some(cgroup) = &cgroup {
+            cgroup.apply(&mut command)?;
+        }
+        if let Some(sandbox) = &sandbox {
+            sandbox.apply(&mut command)?;
+        }
+        set_env(&mut command, port, &secrets);
+        let build = command.output().await?;
 
-        update_logs(&conn, deployment_id, &String::from_utf8_lossy(&build.stdout)).await?;
+        log_output(log, LogPhase::Build, LogStream::Stdout, &String::from_utf8_lossy(&build.stdout)).await?;
+        log_output(log, LogPhase::Build, LogStream::Stderr, &String::from_utf8_lossy(&build.stderr)).await?;
+        report_oom(log, LogPhase::Build, cgroup.as_ref(), &mut oom_kills).await?;
         if !build.status.success() {
-            update_status(&conn, deployment_id, STATUS_FAILED).await?;
-            let error = String::from_utf8_lossy(&build.stderr).to_string();
-            update_logs(&conn, deployment_id, &format!("Error: {}\n", error)).await?;
-            return Err(AppError::Internal(error));
+            cgroup::remove(deployment_id);
+            update_status(conn, deployment_id, STATUS_FAILED).await?;
+            log_error(log, LogPhase::Build, &format!("build command failed with {}", build.status)).await?;
+            return Err(AppError::Internal(String::from_utf8_lossy(&build.stderr).to_string()));
         }
     }
 
-    update_status(&conn, deployment_id, STATUS_RUNNING).await?;
+    fs::write(format!("{}/{}", path, BUILT_MARKER), commit_hash)?;
+    Ok(())
+}
+
+/// Starts a deployment's run command in `app_path` and promotes it once it is
+/// healthy, retiring the project's other deployments.
+async fn launch(
+    state: &AppState,
+    log: DeploymentLog,
+    proj_id: i32,
+    project: Project,
+    path: String,
+    app_path: String,
+) -> Result<(), AppError> {
+    let conn = &log.conn;
+    let deployment_id = log.deployment_id;
+    let policy = RestartPolicy::parse(project.restart_policy.as_deref())?;
+
+    update_status(conn, deployment_id, STATUS_STARTING).await?;
+    // Stopped or deleted while it was building, so there is nothing to start
+    if current_status(conn, deployment_id).await != Some(STATUS_STARTING) {
+        cgroup::remove(deployment_id);
+        return Ok(());
+    }
+    let cgroup = cgroup(&log, &project, LogPhase::Run).await?;
+    let sandbox = sandbox(&log, &project, &app_path, LogPhase::Run).await?;
+    let secrets = secrets(&log, &project, LogPhase::Run).await?;
     let run_cmd = project.run_cmd.ok_or_else(|| AppError::Internal("Run command is required".to_string()))?;
-    update_logs(&conn, deployment_id, &format!("Starting service with: {}\n", run_cmd)).await?;
-    
-    let pid_file = format!("{}/pid", path);
+    update_logs(&log, LogPhase::Run, &format!("Starting service with: {}", run_cmd)).await?;
     
-    let mut run = tokio::process::Command::new("bash")
-        .arg("-c")
-        .arg(&run_cmd)
-        .current_dir(&path)
-        .stdout(Stdio::piped())
-        .stderr(Stdio::piped())
-        .process_group(0)
-        .spawn()?;
-
-    if let Some(pid) = run.id() {
-        fs::write(&pid_file, pid.to_string())?;
+    // Whatever the build was given may have been taken since, so it is checked again
+    let port = port(&log, LogPhase::Run).await?;
+    update_logs(&log, LogPhase::Run, &format!("Run command gets PORT={}", port)).await?;
+    // Two deployments can't both listen on a fixed port, so there is no taking over once
+    // this one is healthy. The one running now makes way first.
+    if let Some(fixed) = project.port {
+        let stopped = stop_deployments_before(conn, &state.logs, proj_id, Some(deployment_id)).await?;
+        if !stopped.is_empty() {
+            update_logs(&log, LogPhase::Run, &format!("The project listens on port {}, stopped deployment(s) {:?} to free it", fixed, stopped)).await?;
+            state.routes.refresh(conn).await;
+        }
     }
+    let run = spawn_run(&run_cmd, &app_path, &path, port, &secrets, cgroup.as_ref(), sandbox.as_ref())?;
+    let (exited_tx, mut exited_rx) = tokio::sync::oneshot::channel();
+    let spec = RunSpec {
+        run_cmd,
+        app_path,
+        path: path.clone(),
+        policy,
+        max_restarts: project.max_restarts.unwrap_or(DEFAULT_MAX_RESTARTS),
+        secrets,
+        cgroup,
+        sandbox,
+        routes: state.routes.clone(),
+    };
+    tokio::spawn(supervise(log.clone(), spec, run, exited_tx));
 
-    let deployment_id_clone = deployment_id;
-    let conn_clone = conn.clone();
-    let pid_file_clone = pid_file.clone();
-    
-    tokio::spawn(async move {
-        let stdout = run.stdout.take().unwrap();
-        let stderr = run.stderr.take().unwrap();
-        
-        let mut stdout_reader = tokio::io::BufReader::new(stdout).lines();
-        let mut stderr_reader = tokio::io::BufReader::new(stderr).lines();
-
-        loop {
-            tokio::select! {
-                Ok(Some(line)) = stdout_reader.next_line() => {
-                    let _ = update_logs(&conn_clone, deployment_id_clone, &format!("{}\n", line)).await;
-                },
-                Ok(Some(line)) = stderr_reader.next_line() => {
-                    let _ = update_logs(&conn_clone, deployment_id_clone, &format!("Error: {}\n", line)).await;
-                },
-                result = run.wait() => {
-                    // Process terminated
-                    let _ = fs::remove_file(pid_file_clone);
-                    let _ = update_status(&conn_clone, deployment_id_clone, STATUS_STOPPED).await;
-                    let status = match result {
-                        Ok(status) => format!("Process exited with status: {}", status),
-                        Err(e) => format!("Process error: {}", e)
-                    };
-                    let _ = update_logs(&conn_clone, deployment_id_clone, &format!("Process terminated: {}\n", status)).await;
-                    break;
-                }
-                else => break,
+    if let Some(endpoint) = project.healthcheck_endpoint.as_deref().filter(|e| !e.trim().is_empty()) {
+        let url = healthcheck_url(endpoint.trim(), project.port.and_then(|port| u16::try_from(port).ok()).unwrap_or(port));
+        let timeout = project.healthcheck_timeout.unwrap_or(DEFAULT_HEALTHCHECK_TIMEOUT);
+        if !wait_healthy(&log, &url, timeout, &mut exited_rx).await? {
+            update_status(conn, deployment_id, STATUS_FAILED).await?;
+            if let Some(pgid) = running_pgid(&path) {
+                let stop_timeout = project.stop_timeout.unwrap_or(DEFAULT_STOP_TIMEOUT);
+                let _ = kill_process_group(&log, pgid, stop_timeout).await;
             }
+            return Err(AppError::Internal(format!("Deployment {} never became healthy", deployment_id)));
         }
-    });
+    }
+
+    // The process may already have exited, in which case the watcher has the
+    // final say. A deployment started later that is already up isn't taken over from.
+    let promoted = conn.execute(
+        "UPDATE deployments SET status = ? WHERE id = ? AND status = ?
+         AND NOT EXISTS (SELECT 1 FROM deployments WHERE project_id = ? AND status = ?
+                         AND sequence > (SELECT sequence FROM deployments WHERE id = ?))",
+        (STATUS_RUNNING, deployment_id, STATUS_STARTING, proj_id, STATUS_RUNNING, deployment_id)
+    ).await?;
+    if promoted > 0 {
+        update_logs(&log, LogPhase::Run, "Deployment is running").await?;
+        events::emit(conn, deployment_id, EventKind::Healthy, "Deployment is running").await;
+        // Only now that the new deployment is up do we retire the previous one, once
+        // the proxy sends requests to the new one rather than the one on its way out
+        state.routes.refresh(conn).await;
+        stop_deployments_before(conn, &state.logs, proj_id, Some(deployment_id)).await?;
+        state.routes.refresh(conn).await;
+    } else if current_status(conn, deployment_id).await == Some(STATUS_STARTING) {
+        update_status(conn, deployment_id, STATUS_STOPPED).await?;
+        update_logs(&log, LogPhase::Run, "A newer deployment is already running, stopping this one").await?;
+        if let Some(pgid) = running_pgid(&path) {
+            let stop_timeout = project.stop_timeout.unwrap_or(DEFAULT_STOP_TIMEOUT);
+            let _ = kill_process_group(&log, pgid, stop_timeout).await;
+        }
+    }
 
     Ok(())
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    /// A project in `state`'s database with the given settings on top of the
+    /// required ones. Its name keeps its directory under projects/ apart.
+    async fn test_project(state: &AppState, name: &str, settings: serde_json::Value) -> Project {
+        let mut project = json!({"name": format!("edgezone-test-{}-{}", name, std::process::id()), "git_repo": "https://example.com/app.git"});
+        project.as_object_mut().unwrap().extend(settings.as_object().unwrap().clone());
+        let mut project: Project = serde_json::from_value(project).unwrap();
+        let conn = state.db.connect().unwrap();
+        conn.execute(
+            "INSERT INTO projects (name, git_repo, run_cmd, restart_policy, max_restarts) VALUES (?, ?, ?, ?, ?)",
+            (project.name.clone(), project.git_repo.clone(), project.run_cmd.clone(), project.restart_policy.clone(), project.max_restarts)
+        ).await.unwrap();
+        project.id = Some(conn.last_insert_rowid() as i32);
+        let _ = fs::remove_dir_all(format!("projects/{}", project.name));
+        project
+    }
 
+    /// A new pending deployment of `project` with an empty checkout to run in.
+    async fn test_deployment(state: &AppState, project: &Project) -> (DeploymentLog, String, String) {
+        let conn = state.db.connect().unwrap();
+        conn.execute(
+            "INSERT INTO deployments (project_id, status, sequence)
+             VALUES (?, ?, (SELECT COALESCE(MAX(sequence), 0) + 1 FROM deployments))",
+            (project.id, STATUS_PENDING)
+        ).await.unwrap();
+        let deployment_id = conn.last_insert_rowid();
+        let path = format!("projects/{}/{}", project.name, deployment_id);
+        let app_path = format!("{}/app", path);
+        fs::create_dir_all(&app_path).unwrap();
+        (DeploymentLog::new(&conn, &state.logs, &project.name, deployment_id), path, app_path)
+    }
+
+    async fn run_test_launch(state: &AppState, project: &Project) -> (i64, String, Result<(), AppError>) {
+        let (log, path, app_path) = test_deployment(state, project).await;
+        let deployment_id = log.deployment_id;
+        let launched = launch(state, log, project.id.unwrap(), project.clone(), path.clone(), app_path).await;
+        (deployment_id, path, launched)
+    }
+
+    /// Serves the deployment directory on PORT once `delay` has passed.
+    fn http_server(delay: &str) -> String {
+        format!("sleep {}; exec python3 -m http.server \"$PORT\" --bind 127.0.0.1", delay)
+    }
+
+    #[test]
+    fn output_is_not_padded_after_truncation() {
+        let dir = std::env::temp_dir().join(format!("edgezone-output-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let file = dir.join("run.stdout").to_string_lossy().to_string();
+        fs::write(&file, "left over from the last run\n").unwrap();
+
+        let mut child = std::process::Command::new("bash")
+            .arg("-c")
+            .arg("echo first; sleep 0.5; echo second")
+            .stdout(open_output(&file).unwrap())
+            .spawn()
+            .unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(200));
+        assert_eq!(fs::read_to_string(&file).unwrap(), "first\n");
+        // The way OutputTail empties it, through a descriptor of its own
+        fs::OpenOptions::new().write(true).open(&file).unwrap().set_len(0).unwrap();
+        assert!(child.wait().unwrap().success());
+
+        assert_eq!(fs::read_to_string(&file).unwrap(), "second\n");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn healthcheck_url_resolves_endpoints() {
+        assert_eq!(healthcheck_url("/health", 23001), "http://127.0.0.1:23001/health");
+        assert_eq!(healthcheck_url("/", 23001), "http://127.0.0.1:23001/");
+        assert_eq!(healthcheck_url(":3000/health", 23001), "http://127.0.0.1:3000/health");
+        assert_eq!(healthcheck_url("http://example.com/up", 23001), "http://example.com/up");
+        assert_eq!(healthcheck_url("https://example.com/up", 23001), "https://example.com/up");
+        assert_eq!(healthcheck_url("localhost:8080/ping", 23001), "http://localhost:8080/ping");
+    }
+
+    #[test]
+    fn valid_git_ref_accepts_refs_only() {
+        for git_ref in ["main", "v1.2.3", "feature/login", "refs/tags/v1", "3f9c2ab", "release-2024.01"] {
+            assert!(valid_git_ref(git_ref), "{}", git_ref);
+        }
+        for git_ref in [
+            "", "-b", "--upload-pack=touch /tmp/x", "main..dev", "a b", "tab\tref", "new\nline", "nul\0",
+            "+refs/heads/x:refs/remotes/origin/main", "main:main", "+main", "main~1", "main^", "v1.*", "ma?n", "[ab]",
+            "back\\slash", "main@{1}", "@", "feature/", "/main", "a//b", ".hidden", "refs/.x", "main.lock", "main.",
+        ] {
+            assert!(!valid_git_ref(git_ref), "{:?}", git_ref);
+        }
+    }
+
+    #[test]
+    fn env_file_replaces_a_planted_symlink() {
+        let dir = std::env::temp_dir().join(format!("edgezone-env-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let target = dir.join("target");
+        fs::write(&target, "keep me").unwrap();
+        fs::set_permissions(&target, fs::Permissions::from_mode(0o644)).unwrap();
+        std::os::unix::fs::symlink(&target, dir.join(".env")).unwrap();
+
+        let dir_path = dir.to_string_lossy();
+        write_owned_file(&dir_path, ".env", "KEY=value\n").unwrap();
+
+        assert_eq!(fs::read_to_string(&target).unwrap(), "keep me");
+        assert_eq!(fs::metadata(&target).unwrap().permissions().mode() & 0o777, 0o644);
+        let env = fs::symlink_metadata(dir.join(".env")).unwrap();
+        assert!(env.file_type().is_file());
+        assert_eq!(env.permissions().mode() & 0o777, 0o600);
+        assert_eq!(fs::read_to_string(dir.join(".env")).unwrap(), "KEY=value\n");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn running_pgid_only_trusts_the_recorded_process() {
+        use std::os::unix::process::CommandExt;
+        let dir = std::env::temp_dir().join(format!("edgezone-pgid-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let path = dir.join("7");
+        fs::create_dir_all(&path).unwrap();
+        let path_str = path.to_string_lossy().to_string();
+        let pid_file = path.join("pid");
+        let mut child = std::process::Command::new("sleep")
+            .arg("30")
+            .current_dir(&path)
+            .process_group(0)
+            .spawn()
+            .unwrap();
+        let pid = child.id();
+        let identity = process_identity(pid).unwrap();
+        let (boot_id, start_time) = identity.split_once(' ').unwrap();
+
+        fs::write(&pid_file, format!("{}\n{}\n", pid, identity)).unwrap();
+        assert_eq!(running_pgid(&path_str), Some(pid as i32));
+        // The same pid in another boot, or started at another time, is someone else
+        fs::write(&pid_file, format!("{}\n{} {}\n", pid, "0".repeat(boot_id.len()), start_time)).unwrap();
+        assert_eq!(running_pgid(&path_str), None);
+        fs::write(&pid_file, format!("{}\n{} 1\n", pid, boot_id)).unwrap();
+        assert_eq!(running_pgid(&path_str), None);
+        // A bare pid, as older nodes wrote it, has to be working in this project
+        fs::write(&pid_file, format!("{}\n", pid)).unwrap();
+        assert_eq!(running_pgid(&path_str), Some(pid as i32));
+        fs::write(&pid_file, format!("{}\n", std::process::id())).unwrap();
+        assert_eq!(running_pgid(&path_str), None);
+
+        fs::write(&pid_file, format!("{}\n{}\n", pid, identity)).unwrap();
+        child.kill().unwrap();
+        child.wait().unwrap();
+        assert_eq!(running_pgid(&path_str), None);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn launch_only_promotes_a_healthy_deployment() {
+        let state = crate::db::test_state("healthcheck").await;
+        let conn = state.db.connect().unwrap();
+
+        let healthy = test_project(&state, "healthy", json!({
+            "run_cmd": http_server("1"), "healthcheck_endpoint": "/", "healthcheck_timeout": 10000,
+        })).await;
+        let (log, path, app_path) = test_deployment(&state, &healthy).await;
+        let id = log.deployment_id;
+        let started = tokio::time::Instant::now();
+        let (launched, status_before) = tokio::join!(
+            launch(&state, log, healthy.id.unwrap(), healthy.clone(), path.clone(), app_path),
+            async {
+                tokio::time::sleep(tokio::time::Duration::from_millis(500)).await;
+                current_status(&conn, id).await
+            },
+        );
+        launched.unwrap();
+        // Not running before the server answered, running once it did
+        assert_eq!(status_before, Some(STATUS_STARTING));
+        assert!(started.elapsed() >= tokio::time::Duration::from_secs(1));
+        assert_eq!(current_status(&conn, id).await, Some(STATUS_RUNNING));
+        let pgid = running_pgid(&path).unwrap();
+        killpg(Pid::from_raw(pgid), Signal::SIGKILL).unwrap();
+
+        // Never answering within the timeout fails it, and its process is stopped
+        let silent = test_project(&state, "silent", json!({
+            "run_cmd": "exec sleep 30", "healthcheck_endpoint": "/", "healthcheck_timeout": 1000,
+        })).await;
+        let (id, path, launched) = run_test_launch(&state, &silent).await;
+        assert!(launched.is_err());
+        assert_eq!(current_status(&conn, id).await, Some(STATUS_FAILED));
+        assert_eq!(running_pgid(&path), None);
+
+        // Exiting before it became healthy doesn't wait for the timeout
+        let exits = test_project(&state, "exits", json!({
+            "run_cmd": "exit 3", "healthcheck_endpoint": "/", "healthcheck_timeout": 30000,
+        })).await;
+        let started = tokio::time::Instant::now();
+        let (id, _, launched) = run_test_launch(&state, &exits).await;
+        assert!(launched.is_err());
+        assert!(started.elapsed() < tokio::time::Duration::from_secs(5));
+        assert_eq!(current_status(&conn, id).await, Some(STATUS_FAILED));
+
+        for project in [healthy, silent, exits] {
+            fs::remove_dir_all(format!("projects/{}", project.name)).unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn launch_retires_the_previous_deployment_once_the_new_one_is_healthy() {
+        let state = crate::db::test_state("cutover").await;
+        let conn = state.db.connect().unwrap();
+        let project = test_project(&state, "cutover", json!({
+            "run_cmd": http_server("1"), "healthcheck_endpoint": "/", "healthcheck_timeout": 10000,
+        })).await;
+        let (old, old_path, launched) = run_test_launch(&state, &project).await;
+        launched.unwrap();
+        assert_eq!(current_status(&conn, old).await, Some(STATUS_RUNNING));
+
+        let (log, path, app_path) = test_deployment(&state, &project).await;
+        let new = log.deployment_id;
+        let (launched, during) = tokio::join!(
+            launch(&state, log, project.id.unwrap(), project.clone(), path.clone(), app_path),
+            async {
+                tokio::time::sleep(tokio::time::Duration::from_millis(500)).await;
+                (current_status(&conn, old).await, running_pgid(&old_path).is_some(), current_status(&conn, new).await)
+            },
+        );
+        launched.unwrap();
+        // The old one kept serving while the new one was starting
+        assert_eq!(during, (Some(STATUS_RUNNING), true, Some(STATUS_STARTING)));
+        assert_eq!(current_status(&conn, new).await, Some(STATUS_RUNNING));
+        assert_eq!(current_status(&conn, old).await, Some(STATUS_STOPPED));
+        assert_eq!(running_pgid(&old_path), None);
+
+        // A replacement that never becomes healthy leaves the running one alone
+        let broken = Project { run_cmd: Some("exit 1".to_string()), ..project.clone() };
+        let (failed, _, launched) = run_test_launch(&state, &broken).await;
+        assert!(launched.is_err());
+        assert_eq!(current_status(&conn, failed).await, Some(STATUS_FAILED));
+        assert_eq!(current_status(&conn, new).await, Some(STATUS_RUNNING));
+        let pgid = running_pgid(&path).unwrap();
+
+        killpg(Pid::from_raw(pgid), Signal::SIGKILL).unwrap();
+        fs::remove_dir_all(format!("projects/{}", project.name)).unwrap();
+    }
+
+    #[test]
+    fn restarts_back_off_up_to_a_minute() {
+        let delays: Vec<u64> = (0..9).map(|consecutive| restart_delay(consecutive).as_secs()).collect();
+        assert_eq!(delays, [1, 2, 4, 8, 16, 32, 60, 60, 60]);
+        assert_eq!(restart_delay(u32::MAX), RESTART_BACKOFF_MAX);
+    }
+
+    #[tokio::test]
+    async fn a_crashing_deployment_is_restarted_until_it_crash_loops() {
+        let state = crate::db::test_state("crashloop").await;
+        let conn = state.db.connect().unwrap();
+        let project = test_project(&state, "crashloop", json!({
+            "run_cmd": "sleep 0.5; exit 7", "restart_policy": "on-failure", "max_restarts": 2,
+        })).await;
+        let started = tokio::time::Instant::now();
+        let (id, _, launched) = run_test_launch(&state, &project).await;
+        launched.unwrap();
+        assert_eq!(current_status(&conn, id).await, Some(STATUS_RUNNING));
+
+        while current_status(&conn, id).await == Some(STATUS_RUNNING) {
+            assert!(started.elapsed() < tokio::time::Duration::from_secs(15), "never gave up restarting");
+            tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+        }
+        assert_eq!(current_status(&conn, id).await, Some(STATUS_CRASHLOOP));
+        // Three runs of half a second with one and then two seconds in between
+        assert!(started.elapsed() >= tokio::time::Duration::from_millis(4500));
+        let restarts: (i64, i64, i64, i64) = {
+            let mut rows = conn.query(
+                "SELECT d.restart_count, COUNT(r.id), MIN(r.exit_code), MAX(r.exit_code) FROM deployments d
+                 JOIN deployment_restarts r ON r.deployment_id = d.id WHERE d.id = ?",
+                [id]
+            ).await.unwrap();
+            let row = rows.next().await.unwrap().unwrap();
+            (row.get(0).unwrap(), row.get(1).unwrap(), row.get(2).unwrap(), row.get(3).unwrap())
+        };
+        assert_eq!(restarts, (2, 2, 7, 7));
+
+        // A clean exit is not a failure, so on-failure leaves it stopped
+        let finishes = test_project(&state, "finishes", json!({"run_cmd": "sleep 0.5", "restart_policy": "on-failure"})).await;
+        let (id, _, launched) = run_test_launch(&state, &finishes).await;
+        launched.unwrap();
+        tokio::time::sleep(tokio::time::Duration::from_millis(1500)).await;
+        assert_eq!(current_status(&conn, id).await, Some(STATUS_STOPPED));
+
+        for project in [project, finishes] {
+            fs::remove_dir_all(format!("projects/{}", project.name)).unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn stopping_escalates_to_sigkill_after_the_grace_period() {
+        let state = crate::db::test_state("kill").await;
+        let project = test_project(&state, "kill", json!({"run_cmd": "true"})).await;
+        let (log, _, _) = test_deployment(&state, &project).await;
+        let stop_messages = || {
+            let dir = crate::logs::log_dir(&project.name, log.deployment_id);
+            let page = crate::logs::read_lines(&dir, &crate::logs::LogFilter::default(), 0, 100).unwrap();
+            page.records.into_iter().map(|record| record.message).collect::<Vec<_>>()
+        };
+
+        // A process that leaves on SIGTERM isn't waited on any longer
+        let mut polite = tokio::process::Command::new("sleep").arg("30").process_group(0).spawn().unwrap();
+        let pgid = polite.id().unwrap() as i32;
+        let started = tokio::time::Instant::now();
+        let (stopped, status) = tokio::join!(kill_process_group(&log, pgid, 5000), polite.wait());
+        stopped.unwrap();
+        assert_eq!(status.unwrap().signal(), Some(Signal::SIGTERM as i32));
+        assert!(started.elapsed() < tokio::time::Duration::from_secs(2));
+        assert!(stop_messages().last().unwrap().starts_with(&format!("Process group {} exited after", pgid)));
+
+        // One that ignores it is killed once the grace period is over
+        let mut stubborn = tokio::process::Command::new("bash")
+            .arg("-c")
+            .arg("trap '' TERM; exec sleep 30")
+            .process_group(0)
+            .spawn()
+            .unwrap();
+        let pgid = stubborn.id().unwrap() as i32;
+        tokio::time::sleep(tokio::time::Duration::from_millis(200)).await;
+        let started = tokio::time::Instant::now();
+        let (stopped, status) = tokio::join!(kill_process_group(&log, pgid, 500), stubborn.wait());
+        stopped.unwrap();
+        assert_eq!(status.unwrap().signal(), Some(Signal::SIGKILL as i32));
+        assert!(started.elapsed() >= tokio::time::Duration::from_millis(500));
+        assert_eq!(
+            stop_messages().last().unwrap(),
+            &format!("Process group {} still running after 500ms, sent SIGKILL", pgid)
+        );
+
+        // And one that is already gone is only noted
+        kill_process_group(&log, pgid, 500).await.unwrap();
+        assert_eq!(stop_messages().last().unwrap(), &format!("Process group {} had already exited", pgid));
+        fs::remove_dir_all(format!("projects/{}", project.name)).unwrap();
+    }
+}
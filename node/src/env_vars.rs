@@ -0,0 +1,277 @@
+use serde::Deserializer;
+use serde_derive::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+use crate::error::AppError;
+
+/// A project's plain environment variables, by name.
+pub type EnvVars = BTreeMap<String, String>;
+
+/// One variable, as the per-key endpoints take and return it.
+#[derive(Serialize, Deserialize)]
+pub struct EnvVar {
+    pub key: Option<String>,
+    pub value: String,
+}
+
+/// Whether `key` can be used as an environment variable name.
+pub fn valid_key(key: &str) -> bool {
+    key.chars().next().is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && key.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+pub fn validate(vars: &EnvVars) -> Result<(), AppError> {
+    // A NUL can't be passed on in an environment
+    if vars.iter().any(|(key, value)| !valid_key(key) || value.contains('\0')) {
+        return Err(AppError::BadRequest);
+    }
+    Ok(())
+}
+
+/// Parses dotenv syntax: `KEY=value` lines with optional `export`, `#`
+/// comments, and single or double quoted values that may span lines. Double
+/// quotes understand `\n`, `\r`, `\t`, `\"` and `\\`. Later keys win.
+pub fn parse(text: &str) -> Result<EnvVars, String> {
+    let mut vars = EnvVars::new();
+    // Files saved on Windows shouldn't leave a \r at the end of every value
+    let text = text.replace("\r\n", "\n");
+    let mut chars = text.chars().peekable();
+    let mut line = 1;
+    loop {
+        // Leading whitespace and blank lines
+        while let Some(&c) = chars.peek() {
+            if c == '\n' {
+                line += 1;
+            } else if !c.is_whitespace() {
+                break;
+            }
+            chars.next();
+        }
+        let Some(&first) = chars.peek() else { break };
+        if first == '#' {
+            while chars.next_if(|&c| c != '\n').is_some() {}
+            continue;
+        }
+
+        let mut name = String::new();
+        while let Some(c) = chars.next_if(|&c| c != '=' && c != '\n') {
+            name.push(c);
+        }
+        if chars.next() != Some('=') {
+            return Err(format!("line {}: expected KEY=value", line));
+        }
+        let name = name.trim();
+        let key = name.strip_prefix("export ").map_or(name, str::trim_start).to_string();
+        if !valid_key(&key) {
+            return Err(format!("line {}: {} is not a valid variable name", line, key));
+        }
+        while chars.next_if(|&c| c == ' ' || c == '\t').is_some() {}
+
+        let start = line;
+        let mut value = String::new();
+        match chars.peek() {
+            Some(&quote @ ('"' | '\'')) => {
+                chars.next();
+                loop {
+                    let c = chars.next().ok_or_else(|| format!("line {}: unterminated {} quote", start, quote))?;
+                    match c {
+                        c if c == quote => break,
+                        '\\' if quote == '"' => match chars.next() {
+                            Some('n') => value.push('\n'),
+                            Some('r') => value.push('\r'),
+                            Some('t') => value.push('\t'),
+                            Some(c @ ('"' | '\\')) => value.push(c),
+                            Some(c) => {
+                                value.push('\\');
+                                value.push(c);
+                            }
+                            None => return Err(format!("line {}: unterminated {} quote", start, quote)),
+                        },
+                        c => {
+                            if c == '\n' {
+                                line += 1;
+                            }
+                            value.push(c);
+                        }
+                    }
+                }
+                // Whatever follows the closing quote can only be a comment
+                let rest: String = std::iter::from_fn(|| chars.next_if(|&c| c != '\n')).collect();
+                let rest = rest.trim();
+                if !rest.is_empty() && !rest.starts_with('#') {
+                    return Err(format!("line {}: unexpected {} after the quoted value", line, rest));
+                }
+            }
+            _ => {
+                while let Some(c) = chars.next_if(|&c| c != '\n') {
+                    value.push(c);
+                }
+                // A # only starts a comment after whitespace, so URLs with fragments survive
+                if value.starts_with('#') {
+                    value.clear();
+                } else if let Some(at) = value.find(" #").or_else(|| value.find("\t#")) {
+                    value.truncate(at);
+                }
+                value = value.trim().to_string();
+            }
+        }
+        vars.insert(key, value);
+    }
+    Ok(vars)
+}
+
+/// Writes `vars` in dotenv syntax that both dotenv libraries and a shell
+/// sourcing the file read back unchanged, as far as that is possible.
+pub fn render(vars: &EnvVars) -> String {
+    let safe = |c: char| c.is_ascii_alphanumeric() || "_-./:@,+%=".contains(c);
+    let mut out = String::new();
+    for (key, value) in vars {
+        out.push_str(key);
+        out.push('=');
+        if value.chars().all(safe) {
+            out.push_str(value);
+        } else if !value.contains(['\'', '\n', '\r']) {
+            out.push('\'');
+            out.push_str(value);
+            out.push('\'');
+        } else {
+            out.push('"');
+            for c in value.chars() {
+                match c {
+                    '\n' => out.push_str("\\n"),
+                    '\r' => out.push_str("\\r"),
+                    '"' => out.push_str("\\\""),
+                    '\\' => out.push_str("\\\\"),
+                    c => out.push(c),
+                }
+            }
+            out.push('"');
+        }
+        out.push('\n');
+    }
+    out
+}
+
+/// Reads the env column, a JSON object. Rows from before env was a map hold
+/// dotenv text, which is read as well as it goes.
+pub fn from_column(value: Option<String>) -> Option<EnvVars> {
+    let value = value?;
+    if value.trim_start().starts_with('{') {
+        serde_json::from_str(&value).ok()
+    } else {
+        Some(parse(&value).unwrap_or_else(|_| {
+            value
+                .lines()
+                .filter_map(|line| line.split_once('='))
+                .map(|(key, value)| (key.trim().to_string(), value.trim().to_string()))
+                .filter(|(key, _)| valid_key(key))
+                .collect()
+        }))
+    }
+}
+
+pub fn to_column(vars: Option<&EnvVars>) -> Option<String> {
+    vars.map(|vars| serde_json::to_string(vars).unwrap_or_default())
+}
+
+/// Takes env either as an object or, the way it used to be sent, as dotenv text.
+pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<EnvVars>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Env {
+        Map(EnvVars),
+        Text(String),
+    }
+    match <Option<Env> as serde::Deserialize>::deserialize(deserializer)? {
+        None => Ok(None),
+        Some(Env::Map(vars)) => Ok(Some(vars)),
+        Some(Env::Text(text)) => parse(&text).map(Some).map_err(serde::de::Error::custom),
+    }
+}
+
+pub async fn load(conn: &libsql::Connection, project_id: i32) -> Result<EnvVars, AppError> {
+    let mut rows = conn.query("SELECT env FROM projects WHERE id = ?", [project_id]).await?;
+    let row = rows.next().await?.ok_or(AppError::NotFound)?;
+    Ok(from_column(row.get(0)?).unwrap_or_default())
+}
+
+pub async fn store(conn: &libsql::Connection, project_id: i32, vars: &EnvVars) -> Result<(), AppError> {
+    validate(vars)?;
+    conn.execute(
+        "UPDATE projects SET env = ? WHERE id = ?",
+        (to_column(Some(vars)), project_id),
+    )
+    .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vars(pairs: &[(&str, &str)]) -> EnvVars {
+        pairs.iter().map(|(key, value)| (key.to_string(), value.to_string())).collect()
+    }
+
+    #[test]
+    fn parses_exports_and_comments() {
+        let text = "# database\nexport DB_HOST=localhost\nexport  DB_PORT = 5432 # default\n\nURL=https://example.com/#top\nEMPTY=\nNOTE= # nothing\n  # indented comment\nDB_HOST=db\n";
+        assert_eq!(
+            parse(text).unwrap(),
+            vars(&[
+                ("DB_HOST", "db"),
+                ("DB_PORT", "5432"),
+                ("EMPTY", ""),
+                ("NOTE", ""),
+                ("URL", "https://example.com/#top"),
+            ])
+        );
+    }
+
+    #[test]
+    fn parses_quoted_values() {
+        let text = "KEY='multi\nline # kept' # comment\nESCAPED=\"a\\nb\\t\\\"c\\\" \\\\ \\$d\"\nRAW='a\\nb'\nSPACES=\"  padded  \"\n";
+        assert_eq!(
+            parse(text).unwrap(),
+            vars(&[
+                ("ESCAPED", "a\nb\t\"c\" \\ \\$d"),
+                ("KEY", "multi\nline # kept"),
+                ("RAW", "a\\nb"),
+                ("SPACES", "  padded  "),
+            ])
+        );
+    }
+
+    #[test]
+    fn parses_crlf() {
+        let text = "# comment\r\nA=1\r\nB='two\r\nlines'\r\nC=\"3\" # note\r\n\r\nD=4";
+        assert_eq!(parse(text).unwrap(), vars(&[("A", "1"), ("B", "two\nlines"), ("C", "3"), ("D", "4")]));
+    }
+
+    #[test]
+    fn rejects_malformed_lines() {
+        assert_eq!(parse("A=1\nNOPE\n").unwrap_err(), "line 2: expected KEY=value");
+        assert!(parse("1A=x").is_err());
+        assert!(parse("A B=x").is_err());
+        assert!(parse("A=\"open\nB=2\n").is_err());
+        assert!(parse("A='x' trailing").is_err());
+    }
+
+    #[test]
+    fn render_round_trips() {
+        let original = vars(&[
+            ("PLAIN", "abc-1.2/x:y@z"),
+            ("EMPTY", ""),
+            ("SINGLE", "it's"),
+            ("DOUBLE", "say \"hi\""),
+            ("BACKSLASH", "C:\\path\\n"),
+            ("NEWLINES", "one\ntwo\r\nthree"),
+            ("LEADING", "  spaced out "),
+            ("HASH", "a #not a comment"),
+            ("MIXED", "'\"\\\n $HOME"),
+        ]);
+        let rendered = render(&original);
+        assert_eq!(parse(&rendered).unwrap(), original);
+        assert_eq!(parse(&rendered.replace('\n', "\r\n")).unwrap(), original);
+    }
+}
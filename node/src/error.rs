@@ -1,4 +1,4 @@
-use axum::http::StatusCode;
+use axum::http::{header, StatusCode};
 use axum::response::{IntoResponse, Response};
 use std::num::ParseIntError;
 
@@ -7,6 +7,7 @@ pub enum AppError {
     Database(libsql::Error),
     NotFound,
     BadRequest,
+    Unauthorized,
     Internal(String),
 }
 
@@ -30,10 +31,18 @@ impl From<ParseIntError> for AppError {
 
 impl IntoResponse for AppError {
     fn into_response(self) -> Response {
+        match &self {
+            AppError::Database(e) => eprintln!("Database error: {}", e),
+            AppError::Internal(e) => eprintln!("Internal error: {}", e),
+            _ => {}
+        }
         let status = match self {
             AppError::Database(_) => StatusCode::INTERNAL_SERVER_ERROR,
             AppError::NotFound => StatusCode::NOT_FOUND,
             AppError::BadRequest => StatusCode::BAD_REQUEST,
+            AppError::Unauthorized => {
+                return (StatusCode::UNAUTHORIZED, [(header::WWW_AUTHENTICATE, "Bearer")]).into_response();
+            }
             AppError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
         };
         status.into_response()
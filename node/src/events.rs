@@ -0,0 +1,458 @@
+use hmac::{Hmac, Mac};
+use serde_derive::{Deserialize, Serialize};
+use sha2::Sha256;
+
+use crate::db::AppState;
+use crate::endpoints::{
+    STATUS_BUILDING, STATUS_CRASHLOOP, STATUS_FAILED, STATUS_INSTALLING, STATUS_PENDING, STATUS_RUNNING,
+    STATUS_STARTING, STATUS_STOPPED,
+};
+use crate::error::AppError;
+
+const DELIVERY_POLL_INTERVAL: std::time::Duration = std::time::Duration::from_secs(5);
+const DELIVERY_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+const DELIVERY_BATCH: i64 = 20;
+/// Deliveries are given up on after this many failed attempts, which with the
+/// backoff below spans about 14 hours.
+const MAX_ATTEMPTS: i64 = 12;
+const RETRY_BACKOFF_BASE: i64 = 30;
+const RETRY_BACKOFF_MAX: i64 = 6 * 60 * 60;
+/// Delivered and abandoned deliveries are kept this long for inspection.
+const DELIVERY_RETENTION: i64 = 7 * 24 * 60 * 60;
+
+/// Something that happened to a deployment.
+#[derive(Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EventKind {
+    DeployStarted,
+    /// Moved on to installing, building or starting.
+    PhaseChanged,
+    Failed,
+    /// Passed its healthcheck and took over from the previous deployment.
+    Healthy,
+    /// The run process exited unexpectedly.
+    Crashed,
+    Restarted,
+    Stopped,
+}
+
+impl EventKind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            EventKind::DeployStarted => "deploy_started",
+            EventKind::PhaseChanged => "phase_changed",
+            EventKind::Failed => "failed",
+            EventKind::Healthy => "healthy",
+            EventKind::Crashed => "crashed",
+            EventKind::Restarted => "restarted",
+            EventKind::Stopped => "stopped",
+        }
+    }
+
+    /// The event a deployment moving to `status` amounts to, if any.
+    pub fn for_status(status: i32) -> Option<(Self, &'static str)> {
+        match status {
+            STATUS_INSTALLING => Some((EventKind::PhaseChanged, "Running the install command")),
+            STATUS_BUILDING => Some((EventKind::PhaseChanged, "Running the build command")),
+            STATUS_STARTING => Some((EventKind::PhaseChanged, "Starting the service")),
+            STATUS_FAILED => Some((EventKind::Failed, "Deployment failed")),
+            STATUS_STOPPED => Some((EventKind::Stopped, "Deployment stopped")),
+            STATUS_CRASHLOOP => Some((EventKind::Failed, "Deployment is crash looping")),
+            _ => None,
+        }
+    }
+}
+
+fn status_name(status: i32) -> &'static str {
+    match status {
+        STATUS_PENDING => "pending",
+        STATUS_INSTALLING => "installing",
+        STATUS_BUILDING => "building",
+        STATUS_RUNNING => "running",
+        STATUS_FAILED => "failed",
+        STATUS_STOPPED => "stopped",
+        STATUS_STARTING => "starting",
+        STATUS_CRASHLOOP => "crashloop",
+        _ => "unknown",
+    }
+}
+
+/// The body POSTed to webhooks.
+#[derive(Serialize)]
+pub struct Event {
+    pub id: String,
+    #[serde(rename = "type")]
+    pub kind: EventKind,
+    pub project_id: i32,
+    pub project: String,
+    pub deployment_id: i64,
+    pub status: &'static str,
+    pub message: String,
+    pub timestamp: String,
+}
+
+/// A URL that events are POSTed to, for one project or for all of them.
+/// `events` limits it to those types, every type when empty.
+#[derive(Serialize, Deserialize)]
+pub struct Webhook {
+    pub id: Option<i64>,
+    pub url: String,
+    pub project_id: Option<i32>,
+    #[serde(default)]
+    pub events: Vec<EventKind>,
+    /// The HMAC key deliveries are signed with. Generated when not given, and
+    /// only ever shown in the response that created the webhook.
+    pub secret: Option<String>,
+    pub created_at: Option<String>,
+}
+
+impl Webhook {
+    pub fn validate(&self) -> Result<(), AppError> {
+        let url = self.url.trim();
+        if !(url.starts_with("http://") || url.starts_with("https://")) {
+            return Err(AppError::BadRequest);
+        }
+        if self.secret.as_deref().is_some_and(str::is_empty) {
+            return Err(AppError::BadRequest);
+        }
+        Ok(())
+    }
+
+    fn from_row(row: &libsql::Row) -> Result<Self, AppError> {
+        let events: String = row.get::<Option<String>>(3)?.unwrap_or_default();
+        Ok(Webhook {
+            id: row.get(0)?,
+            url: row.get(1)?,
+            project_id: row.get(2)?,
+            events: serde_json::from_str(&events).unwrap_or_default(),
+            secret: None,
+            created_at: row.get(4)?,
+        })
+    }
+}
+
+/// One attempt at getting an event to a webhook, from the outbox.
+#[derive(Serialize)]
+pub struct Delivery {
+    pub id: i64,
+    pub webhook_id: i64,
+    pub event_type: String,
+    pub payload: String,
+    pub attempts: i64,
+    pub last_error: Option<String>,
+    /// Unix timestamps.
+    pub next_attempt: Option<i64>,
+    pub delivered_at: Option<i64>,
+    pub failed_at: Option<i64>,
+    pub created_at: String,
+}
+
+pub async fn create_webhook(conn: &libsql::Connection, mut webhook: Webhook) -> Result<Webhook, AppError> {
+    webhook.validate()?;
+    if let Some(project_id) = webhook.project_id {
+        let mut rows = conn.query("SELECT id FROM projects WHERE id = ?", [project_id]).await?;
+        rows.next().await?.ok_or(AppError::NotFound)?;
+        drop(rows);
+    }
+    let secret = webhook
+        .secret
+        .take()
+        .unwrap_or_else(|| hex::encode(rand::random::<[u8; 32]>()));
+    conn.execute(
+        "INSERT INTO webhooks (url, project_id, events, secret) VALUES (?, ?, ?, ?)",
+        (
+            webhook.url.trim().to_string(),
+            webhook.project_id,
+            serde_json::to_string(&webhook.events).map_err(|e| AppError::Internal(e.to_string()))?,
+            secret.clone(),
+        ),
+    )
+    .await?;
+    let id = conn.last_insert_rowid();
+    let mut webhook = get_webhook(conn, id).await?;
+    webhook.secret = Some(secret);
+    Ok(webhook)
+}
+
+pub async fn get_webhook(conn: &libsql::Connection, id: i64) -> Result<Webhook, AppError> {
+    let mut rows = conn
+        .query("SELECT id, url, project_id, events, created_at FROM webhooks WHERE id = ?", [id])
+        .await?;
+    let row = rows.next().await?.ok_or(AppError::NotFound)?;
+    Webhook::from_row(&row)
+}
+
+pub async fn list_webhooks(conn: &libsql::Connection) -> Result<Vec<Webhook>, AppError> {
+    let mut rows = conn
+        .query("SELECT id, url, project_id, events, created_at FROM webhooks ORDER BY id", ())
+        .await?;
+    let mut webhooks = Vec::new();
+    while let Some(row) = rows.next().await? {
+        webhooks.push(Webhook::from_row(&row)?);
+    }
+    Ok(webhooks)
+}
+
+/// Deletes a webhook along with whatever it still had queued.
+pub async fn delete_webhook(conn: &libsql::Connection, id: i64) -> Result<(), AppError> {
+    conn.execute("DELETE FROM webhook_deliveries WHERE webhook_id = ?", [id]).await?;
+    if conn.execute("DELETE FROM webhooks WHERE id = ?", [id]).await? == 0 {
+        return Err(AppError::NotFound);
+    }
+    Ok(())
+}
+
+/// The latest deliveries of a webhook, newest first.
+pub async fn list_deliveries(conn: &libsql::Connection, webhook_id: i64, limit: i64) -> Result<Vec<Delivery>, AppError> {
+    get_webhook(conn, webhook_id).await?;
+    let mut rows = conn
+        .query(
+            "SELECT id, webhook_id, event_type, payload, attempts, last_error, next_attempt, delivered_at, failed_at, created_at
+             FROM webhook_deliveries WHERE webhook_id = ? ORDER BY id DESC LIMIT ?",
+            (webhook_id, limit),
+        )
+        .await?;
+    let mut deliveries = Vec::new();
+    while let Some(row) = rows.next().await? {
+        deliveries.push(Delivery {
+            id: row.get(0)?,
+            webhook_id: row.get(1)?,
+            event_type: row.get(2)?,
+            payload: row.get(3)?,
+            attempts: row.get(4)?,
+            last_error: row.get(5)?,
+            next_attempt: row.get(6)?,
+            delivered_at: row.get(7)?,
+            failed_at: row.get(8)?,
+            created_at: row.get(9)?,
+        });
+    }
+    Ok(deliveries)
+}
+
+/// Queues `kind` for every webhook interested in the deployment's project.
+/// Failing to do so is logged rather than failing whatever caused the event.
+pub async fn emit(conn: &libsql::Connection, deployment_id: i64, kind: EventKind, message: &str) {
+    if let Err(e) = enqueue(conn, deployment_id, kind, message).await {
+        eprintln!("Failed to queue {} event of deployment {}: {:?}", kind.name(), deployment_id, e);
+    }
+}
+
+async fn enqueue(conn: &libsql::Connection, deployment_id: i64, kind: EventKind, message: &str) -> Result<(), AppError> {
+    // Rows keep their statement, and with it a read transaction, open until dropped
+    let (project_id, project, status) = {
+        let mut rows = conn
+            .query(
+                "SELECT p.id, p.name, d.status FROM deployments d
+                 INNER JOIN projects p ON p.id = d.project_id WHERE d.id = ?",
+                [deployment_id],
+            )
+            .await?;
+        let Some(row) = rows.next().await? else { return Ok(()) };
+        (row.get::<i32>(0)?, row.get::<String>(1)?, row.get::<Option<i32>>(2)?.unwrap_or(STATUS_PENDING))
+    };
+    let webhooks = {
+        let mut rows = conn
+            .query("SELECT id, events FROM webhooks WHERE project_id IS NULL OR project_id = ?", [project_id])
+            .await?;
+        let mut webhooks = Vec::new();
+        while let Some(row) = rows.next().await? {
+            let events: Vec<EventKind> = serde_json::from_str(&row.get::<Option<String>>(1)?.unwrap_or_default()).unwrap_or_default();
+            if events.is_empty() || events.contains(&kind) {
+                webhooks.push(row.get::<i64>(0)?);
+            }
+        }
+        webhooks
+    };
+    if webhooks.is_empty() {
+        return Ok(());
+    }
+
+    let event = Event {
+        id: hex::encode(rand::random::<[u8; 16]>()),
+        kind,
+        project_id,
+        project,
+        deployment_id,
+        status: status_name(status),
+        message: message.to_string(),
+        timestamp: chrono::Utc::now().to_rfc3339(),
+    };
+    let payload = serde_json::to_string(&event).map_err(|e| AppError::Internal(e.to_string()))?;
+    let now = chrono::Utc::now().timestamp();
+    for webhook_id in webhooks {
+        conn.execute(
+            "INSERT INTO webhook_deliveries (webhook_id, event_type, payload, next_attempt) VALUES (?, ?, ?, ?)",
+            (webhook_id, kind.name(), payload.clone(), now),
+        )
+        .await?;
+    }
+    Ok(())
+}
+
+/// Works through the outbox, POSTing due deliveries and scheduling retries
+/// with exponential backoff for the ones that fail.
+pub async fn deliver(state: AppState) {
+    let client = match reqwest::Client::builder().timeout(DELIVERY_TIMEOUT).build() {
+        Ok(client) => client,
+        Err(e) => {
+            eprintln!("Webhooks are disabled, failed to set up the HTTP client: {}", e);
+            return;
+        }
+    };
+    let mut interval = tokio::time::interval(DELIVERY_POLL_INTERVAL);
+    loop {
+        interval.tick().await;
+        if let Err(e) = deliver_due(&state, &client).await {
+            eprintln!("Webhook delivery error: {:?}", e);
+        }
+    }
+}
+
+async fn deliver_due(state: &AppState, client: &reqwest::Client) -> Result<(), AppError> {
+    let conn = state.db.connect()?;
+    conn.query("PRAGMA busy_timeout = 10000", ()).await?;
+    let now = chrono::Utc::now().timestamp();
+    let due = {
+        let mut rows = conn
+            .query(
+                "SELECT d.id, d.event_type, d.payload, d.attempts, w.url, w.secret
+                 FROM webhook_deliveries d INNER JOIN webhooks w ON w.id = d.webhook_id
+                 WHERE d.delivered_at IS NULL AND d.failed_at IS NULL AND d.next_attempt <= ?
+                 ORDER BY d.id LIMIT ?",
+                (now, DELIVERY_BATCH),
+            )
+            .await?;
+        let mut due = Vec::new();
+        while let Some(row) = rows.next().await? {
+            due.push((
+                row.get::<i64>(0)?,
+                row.get::<String>(1)?,
+                row.get::<String>(2)?,
+                row.get::<i64>(3)?,
+                row.get::<String>(4)?,
+                row.get::<String>(5)?,
+            ));
+        }
+        due
+    };
+
+    for (id, event_type, payload, attempts, url, secret) in due {
+        let attempts = attempts + 1;
+        match post(client, id, &event_type, &payload, &url, &secret).await {
+            Ok(()) => {
+                conn.execute(
+                    "UPDATE webhook_deliveries SET attempts = ?, delivered_at = ?, last_error = NULL WHERE id = ?",
+                    (attempts, chrono::Utc::now().timestamp(), id),
+                )
+                .await?;
+            }
+            Err(error) if gives_up(attempts) => {
+                eprintln!("Giving up on delivering {} event to {} after {} attempts: {}", event_type, url, attempts, error);
+                conn.execute(
+                    "UPDATE webhook_deliveries SET attempts = ?, failed_at = ?, last_error = ? WHERE id = ?",
+                    (attempts, chrono::Utc::now().timestamp(), error, id),
+                )
+                .await?;
+            }
+            Err(error) => {
+                let delay = retry_delay(attempts);
+                conn.execute(
+                    "UPDATE webhook_deliveries SET attempts = ?, next_attempt = ?, last_error = ? WHERE id = ?",
+                    (attempts, chrono::Utc::now().timestamp() + delay, error, id),
+                )
+                .await?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Whether a delivery that failed for the `attempts`th time is given up on.
+fn gives_up(attempts: i64) -> bool {
+    attempts >= MAX_ATTEMPTS
+}
+
+/// Seconds until a delivery that failed for the `attempts`th time is tried again.
+fn retry_delay(attempts: i64) -> i64 {
+    RETRY_BACKOFF_BASE.saturating_mul(1 << (attempts - 1).clamp(0, 16)).min(RETRY_BACKOFF_MAX)
+}
+
+/// The hex HMAC-SHA256 of `<timestamp>.<payload>`. It covers the timestamp as
+/// well as the body, so receivers can reject both tampered and replayed requests.
+fn sign(secret: &str, timestamp: &str, payload: &str) -> Result<String, String> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).map_err(|e| e.to_string())?;
+    mac.update(timestamp.as_bytes());
+    mac.update(b".");
+    mac.update(payload.as_bytes());
+    Ok(hex::encode(mac.finalize().into_bytes()))
+}
+
+/// POSTs one delivery, signed with the webhook's secret.
+async fn post(client: &reqwest::Client, id: i64, event_type: &str, payload: &str, url: &str, secret: &str) -> Result<(), String> {
+    let timestamp = chrono::Utc::now().timestamp().to_string();
+    let signature = sign(secret, &timestamp, payload)?;
+
+    let response = client
+        .post(url)
+        .header("Content-Type", "application/json")
+        .header("User-Agent", concat!("edgezone-node/", env!("CARGO_PKG_VERSION")))
+        .header("X-EdgeZone-Event", event_type)
+        .header("X-EdgeZone-Delivery", id.to_string())
+        .header("X-EdgeZone-Timestamp", &timestamp)
+        .header("X-EdgeZone-Signature", format!("sha256={}", signature))
+        .body(payload.to_string())
+        .send()
+        .await
+        .map_err(|e| e.to_string())?;
+    if !response.status().is_success() {
+        return Err(format!("{} responded with {}", url, response.status()));
+    }
+    Ok(())
+}
+
+/// Forgets deliveries that were delivered or given up on a while ago.
+pub async fn prune_deliveries(conn: &libsql::Connection) -> Result<u64, AppError> {
+    let cutoff = chrono::Utc::now().timestamp() - DELIVERY_RETENTION;
+    Ok(conn
+        .execute(
+            "DELETE FROM webhook_deliveries WHERE delivered_at < ? OR failed_at < ?",
+            (cutoff, cutoff),
+        )
+        .await?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn signs_timestamp_and_body() {
+        assert_eq!(
+            sign("whsec_test", "1700000000", r#"{"type":"healthy"}"#).unwrap(),
+            "746156934bfb4a0e7c2d2167679b137cf0adabac82b7f0afbd84b86898d48360"
+        );
+        // Neither part can be changed without breaking the signature
+        let signature = sign("whsec_test", "1700000000", "{}").unwrap();
+        assert_ne!(sign("whsec_test", "1700000001", "{}").unwrap(), signature);
+        assert_ne!(sign("whsec_test", "1700000000", "{} ").unwrap(), signature);
+        assert_ne!(sign("other", "1700000000", "{}").unwrap(), signature);
+    }
+
+    #[test]
+    fn retries_back_off_exponentially_up_to_the_cap() {
+        let schedule: Vec<i64> = (1..MAX_ATTEMPTS).map(retry_delay).collect();
+        assert_eq!(schedule, [30, 60, 120, 240, 480, 960, 1920, 3840, 7680, 15360, RETRY_BACKOFF_MAX]);
+        assert_eq!(retry_delay(40), RETRY_BACKOFF_MAX);
+        assert_eq!(retry_delay(0), RETRY_BACKOFF_BASE);
+        // Between the first attempt and the last one, more than half a day goes by
+        assert!(schedule.iter().sum::<i64>() > 12 * 60 * 60);
+    }
+
+    #[test]
+    fn gives_up_after_twelve_attempts() {
+        assert!((1..12).all(|attempts| !gives_up(attempts)));
+        assert!(gives_up(12));
+        assert!(gives_up(13));
+    }
+}
@@ -0,0 +1,600 @@
+use std::{
+    collections::HashMap,
+    fs,
+    io::{Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+use serde_derive::{Deserialize, Serialize};
+use tokio::sync::{broadcast, mpsc};
+
+use crate::db::AppState;
+use crate::error::AppError;
+
+const CHANNEL_CAPACITY: usize = 256;
+/// A segment is closed and a new one started once it grows past this.
+const SEGMENT_SIZE: u64 = 1024 * 1024;
+/// Older segments beyond this many are deleted.
+const MAX_SEGMENTS: usize = 5;
+/// How much of the system messages the deployments.logs column keeps.
+const SUMMARY_SIZE: i32 = 8192;
+
+/// Where a log record came from: the deployed process or the node itself.
+#[derive(Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogStream {
+    Stdout,
+    Stderr,
+    System,
+}
+
+/// The step of a deployment a log record belongs to.
+#[derive(Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogPhase {
+    Clone,
+    Install,
+    Build,
+    Run,
+    Healthcheck,
+    Stop,
+}
+
+/// How the node rates a record. Output of the deployed process is always
+/// `info`, its stream tells stdout and stderr apart.
+#[derive(Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogLevel {
+    Info,
+    Warn,
+    Error,
+}
+
+/// One line of a deployment's log. Log files hold one of these per line, as JSON.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct LogRecord {
+    pub ts: String,
+    pub stream: LogStream,
+    pub phase: LogPhase,
+    pub level: LogLevel,
+    pub message: String,
+}
+
+/// Narrows a log down to one phase and/or stream.
+#[derive(Clone, Copy, Default, Deserialize)]
+pub struct LogFilter {
+    pub phase: Option<LogPhase>,
+    pub stream: Option<LogStream>,
+}
+
+impl LogFilter {
+    fn matches(&self, record: &LogRecord) -> bool {
+        self.phase.is_none_or(|phase| phase == record.phase)
+            && self.stream.is_none_or(|stream| stream == record.stream)
+    }
+}
+
+/// Log records read from `offset` up to `next`, the offset to resume from.
+#[derive(Serialize)]
+pub struct LogPage {
+    pub offset: u64,
+    pub next: u64,
+    pub records: Vec<LogRecord>,
+}
+
+impl LogPage {
+    /// Records of `chunk` matching `filter`. A line cut off at either end of
+    /// the chunk is skipped.
+    pub fn from_chunk(chunk: &LogChunk, filter: &LogFilter) -> LogPage {
+        LogPage {
+            offset: chunk.offset,
+            next: chunk.end(),
+            records: parse_records(chunk)
+                .into_iter()
+                .map(|(_, record)| record)
+                .filter(|record| filter.matches(record))
+                .collect(),
+        }
+    }
+
+    pub fn to_json_lines(&self) -> String {
+        self.records
+            .iter()
+            .filter_map(|record| serde_json::to_string(record).ok())
+            .map(|line| line + "\n")
+            .collect()
+    }
+}
+
+/// A piece of a deployment's log. `offset` is the byte position of `text` in
+/// the full log, so a client that saw everything up to `offset + text.len()`
+/// can resume from there.
+#[derive(Clone, Serialize)]
+pub struct LogChunk {
+    pub offset: u64,
+    pub text: String,
+}
+
+impl LogChunk {
+    pub fn end(&self) -> u64 {
+        self.offset + self.text.len() as u64
+    }
+
+    /// Drops whatever comes before `position`.
+    fn starting_at(self, position: u64) -> LogChunk {
+        if position <= self.offset {
+            return self;
+        }
+        let skip = ((position - self.offset) as usize).min(self.text.len());
+        LogChunk {
+            offset: position,
+            text: String::from_utf8_lossy(&self.text.as_bytes()[skip..]).into_owned(),
+        }
+    }
+}
+
+/// Fans out freshly written log lines to everyone tailing a deployment.
+#[derive(Clone, Default)]
+pub struct LogHub {
+    channels: Arc<Mutex<HashMap<i64, broadcast::Sender<LogChunk>>>>,
+    /// Serializes appends so offsets in the log files stay consistent.
+    write_lock: Arc<Mutex<()>>,
+}
+
+impl LogHub {
+    pub fn publish(&self, deployment_id: i64, chunk: LogChunk) {
+        let mut channels = self.channels.lock().unwrap();
+        if let Some(sender) = channels.get(&deployment_id) {
+            if sender.send(chunk).is_err() {
+                // Nobody is listening anymore
+                channels.remove(&deployment_id);
+            }
+        }
+    }
+
+    pub fn subscribe(&self, deployment_id: i64) -> broadcast::Receiver<LogChunk> {
+        self.channels
+            .lock()
+            .unwrap()
+            .entry(deployment_id)
+            .or_insert_with(|| broadcast::channel(CHANNEL_CAPACITY).0)
+            .subscribe()
+    }
+}
+
+/// Directory holding the log segments of a deployment.
+pub fn log_dir(project_name: &str, deployment_id: i64) -> PathBuf {
+    PathBuf::from(format!("projects/{}/{}/logs", project_name, deployment_id))
+}
+
+/// Log segments in order, each with the absolute offset it starts at. Segment
+/// files are named after that offset.
+fn segments(dir: &Path) -> Vec<(u64, PathBuf)> {
+    let mut segments: Vec<(u64, PathBuf)> = fs::read_dir(dir)
+        .into_iter()
+        .flatten()
+        .flatten()
+        .filter_map(|entry| {
+            let path = entry.path();
+            let start = path.file_stem()?.to_str()?.parse().ok()?;
+            (path.extension()? == "log").then_some((start, path))
+        })
+        .collect();
+    segments.sort();
+    segments
+}
+
+fn segment_path(dir: &Path, start: u64) -> PathBuf {
+    dir.join(format!("{:020}.log", start))
+}
+
+/// Total size of the log, rotated segments included.
+fn log_end(dir: &Path) -> u64 {
+    segments(dir)
+        .last()
+        .map(|(start, path)| start + fs::metadata(path).map(|m| m.len()).unwrap_or(0))
+        .unwrap_or(0)
+}
+
+/// Writes `text` at the end of the log, rotating segments as needed, and
+/// returns the offset it was written at.
+fn write_segment(dir: &Path, text: &str) -> std::io::Result<u64> {
+    fs::create_dir_all(dir)?;
+    let mut segments = segments(dir);
+    let (mut start, mut path) = segments.last().cloned().unwrap_or_else(|| (0, segment_path(dir, 0)));
+    let mut size = fs::metadata(&path).map(|m| m.len()).unwrap_or(0);
+
+    if size > 0 && size + text.len() as u64 > SEGMENT_SIZE {
+        start += size;
+        size = 0;
+        path = segment_path(dir, start);
+        segments.push((start, path.clone()));
+        let excess = segments.len().saturating_sub(MAX_SEGMENTS);
+        for (_, old) in segments.drain(..excess) {
+            let _ = fs::remove_file(old);
+        }
+    }
+
+    let mut file = fs::OpenOptions::new().create(true).append(true).open(&path)?;
+    file.write_all(text.as_bytes())?;
+    Ok(start + size)
+}
+
+/// Turns every line of `text` into a record, one JSON object per line.
+fn format_lines(stream: LogStream, phase: LogPhase, level: LogLevel, text: &str) -> String {
+    let ts = chrono::Utc::now().format("%Y-%m-%dT%H:%M:%S%.3fZ").to_string();
+    text.lines()
+        .filter_map(|line| {
+            let record = LogRecord {
+                ts: ts.clone(),
+                stream,
+                phase,
+                level,
+                message: line.to_string(),
+            };
+            serde_json::to_string(&record).ok()
+        })
+        .map(|line| line + "\n")
+        .collect()
+}
+
+/// Every complete record in `chunk`, with the offset it starts at.
+fn parse_records(chunk: &LogChunk) -> Vec<(u64, LogRecord)> {
+    let mut records = Vec::new();
+    let mut start = 0;
+    for (end, _) in chunk.text.match_indices('\n') {
+        if let Ok(record) = serde_json::from_str(&chunk.text[start..end]) {
+            records.push((chunk.offset + start as u64, record));
+        }
+        start = end + 1;
+    }
+    records
+}
+
+/// Where a deployment's log lines go: its log files and anyone following them.
+#[derive(Clone)]
+pub struct DeploymentLog {
+    pub conn: libsql::Connection,
+    hub: LogHub,
+    dir: PathBuf,
+    pub deployment_id: i64,
+}
+
+impl DeploymentLog {
+    pub fn new(conn: &libsql::Connection, hub: &LogHub, project_name: &str, deployment_id: i64) -> Self {
+        DeploymentLog {
+            conn: conn.clone(),
+            hub: hub.clone(),
+            dir: log_dir(project_name, deployment_id),
+            deployment_id,
+        }
+    }
+
+    /// Appends a record per line of `text` to the log files and tells the
+    /// followers about it. System messages also go to the deployments.logs
+    /// summary.
+    pub async fn append(&self, stream: LogStream, phase: LogPhase, level: LogLevel, text: &str) -> Result<(), AppError> {
+        if text.is_empty() {
+            return Ok(());
+        }
+        let lines = format_lines(stream, phase, level, text);
+        let offset = {
+            let _guard = self.hub.write_lock.lock().unwrap();
+            write_segment(&self.dir, &lines)?
+        };
+        self.hub.publish(self.deployment_id, LogChunk { offset, text: lines });
+
+        if stream == LogStream::System {
+            self.summarize(level, text).await?;
+        }
+        Ok(())
+    }
+
+    async fn summarize(&self, level: LogLevel, text: &str) -> Result<(), AppError> {
+        let summary = if level == LogLevel::Error {
+            format!("Error: {}\n", text.trim_end())
+        } else {
+            format!("{}\n", text.trim_end())
+        };
+        self.conn
+            .execute(
+                "UPDATE deployments SET logs = substr(logs || ?, -?) WHERE id = ?",
+                (summary, SUMMARY_SIZE, self.deployment_id),
+            )
+            .await?;
+        Ok(())
+    }
+}
+
+/// Reads up to `limit` bytes of the log starting at `offset`. Offsets that
+/// were already rotated away start at the oldest byte still on disk.
+fn read_range(dir: &Path, offset: u64, limit: u64) -> std::io::Result<LogChunk> {
+    let segments = segments(dir);
+    let first = segments.first().map(|(start, _)| *start).unwrap_or(0);
+    let start = offset.max(first);
+    let mut position = start;
+    let mut bytes = Vec::new();
+
+    for (i, (segment_start, path)) in segments.iter().enumerate() {
+        let end = segments.get(i + 1).map(|(next, _)| *next).unwrap_or(u64::MAX);
+        if position >= end || bytes.len() as u64 >= limit {
+            continue;
+        }
+        let mut file = fs::File::open(path)?;
+        file.seek(SeekFrom::Start(position.saturating_sub(*segment_start)))?;
+        let want = limit - bytes.len() as u64;
+        let read = file.take(want).read_to_end(&mut bytes)?;
+        position += read as u64;
+    }
+
+    Ok(LogChunk {
+        offset: start,
+        text: String::from_utf8_lossy(&bytes).into_owned(),
+    })
+}
+
+/// Reads the records in up to `limit` bytes of the log starting at `offset`.
+/// `next` stops short of a record cut off by the limit.
+pub fn read_page(dir: &Path, filter: &LogFilter, offset: u64, limit: u64) -> std::io::Result<LogPage> {
+    let mut chunk = read_range(dir, offset, limit)?;
+    if let Some(last) = chunk.text.rfind('\n') {
+        chunk.text.truncate(last + 1);
+    }
+    Ok(LogPage::from_chunk(&chunk, filter))
+}
+
+/// Reads `count` records matching `filter`, starting at the `line`th of them
+/// still on disk. A negative `line` counts from the end, so `-100` is the
+/// last 100 records.
+pub fn read_lines(dir: &Path, filter: &LogFilter, line: i64, count: usize) -> std::io::Result<LogPage> {
+    let all = read_range(dir, 0, u64::MAX)?;
+    let mut records: Vec<(u64, LogRecord)> = parse_records(&all)
+        .into_iter()
+        .filter(|(_, record)| filter.matches(record))
+        .collect();
+
+    let first = if line < 0 {
+        records.len().saturating_sub(line.unsigned_abs() as usize)
+    } else {
+        (line as usize).min(records.len())
+    };
+    let last = first.saturating_add(count).min(records.len());
+    // Resume right after the last record returned, or at the end of the log
+    let next = records.get(last).map(|(offset, _)| *offset).unwrap_or(all.end());
+    let offset = records.get(first).map(|(offset, _)| *offset).unwrap_or(next);
+
+    Ok(LogPage {
+        offset,
+        next,
+        records: records.drain(first..last).map(|(_, record)| record).collect(),
+    })
+}
+
+/// Streams a deployment's logs starting at byte `since`: first what is
+/// already stored, then every new chunk as it is written. The stream ends
+/// when the receiver is dropped, even if nothing is written anymore.
+pub async fn follow(
+    state: &AppState,
+    dir: PathBuf,
+    deployment_id: i64,
+    since: u64,
+) -> Result<mpsc::Receiver<LogChunk>, AppError> {
+    // Subscribe before reading the backlog so nothing written in between is lost
+    let mut live = state.logs.subscribe(deployment_id);
+    let backlog = read_range(&dir, since, u64::MAX)?;
+
+    let (tx, rx) = mpsc::channel(CHANNEL_CAPACITY);
+    tokio::spawn(async move {
+        // Asking for more than is there yet means waiting for it
+        let mut position = backlog.end().max(since);
+        if !backlog.text.is_empty() && tx.send(backlog).await.is_err() {
+            return;
+        }
+
+        loop {
+            let received = tokio::select! {
+                received = live.recv() => received,
+                // A finished deployment publishes nothing more, so notice the
+                // reader leaving without waiting for the next chunk
+                _ = tx.closed() => return,
+            };
+            let chunk = match received {
+                Ok(chunk) if chunk.end() <= position => continue,
+                Ok(chunk) if chunk.offset <= position => chunk,
+                // Fell behind or writes were published out of order, catch up from disk
+                Ok(_) | Err(broadcast::error::RecvError::Lagged(_)) => {
+                    if log_end(&dir) <= position {
+                        continue;
+                    }
+                    match read_range(&dir, position, u64::MAX) {
+                        Ok(chunk) => chunk,
+                        Err(_) => return,
+                    }
+                }
+                Err(broadcast::error::RecvError::Closed) => return,
+            };
+            let chunk = chunk.starting_at(position);
+            position = chunk.end();
+            if !chunk.text.is_empty() && tx.send(chunk).await.is_err() {
+                return;
+            }
+        }
+    });
+
+    Ok(rx)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("edgezone-logs-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn write(dir: &Path, stream: LogStream, phase: LogPhase, messages: &[&str]) -> u64 {
+        write_segment(dir, &format_lines(stream, phase, LogLevel::Info, &messages.join("\n"))).unwrap()
+    }
+
+    fn messages(page: &LogPage) -> Vec<&str> {
+        page.records.iter().map(|record| record.message.as_str()).collect()
+    }
+
+    #[test]
+    fn read_lines_counts_matching_records() {
+        let dir = test_dir("lines");
+        write(&dir, LogStream::System, LogPhase::Build, &["building"]);
+        write(&dir, LogStream::Stdout, LogPhase::Run, &["one", "two"]);
+        write(&dir, LogStream::Stderr, LogPhase::Run, &["oops"]);
+        write(&dir, LogStream::Stdout, LogPhase::Run, &["three"]);
+        let all = LogFilter::default();
+        let stdout = LogFilter { stream: Some(LogStream::Stdout), ..Default::default() };
+        let run = LogFilter { phase: Some(LogPhase::Run), ..Default::default() };
+
+        assert_eq!(messages(&read_lines(&dir, &all, 0, 100).unwrap()), ["building", "one", "two", "oops", "three"]);
+        assert_eq!(messages(&read_lines(&dir, &all, 1, 2).unwrap()), ["one", "two"]);
+        assert_eq!(messages(&read_lines(&dir, &all, -2, 100).unwrap()), ["oops", "three"]);
+        assert_eq!(messages(&read_lines(&dir, &all, -100, 1).unwrap()), ["building"]);
+        assert_eq!(messages(&read_lines(&dir, &stdout, -2, 100).unwrap()), ["two", "three"]);
+        assert_eq!(messages(&read_lines(&dir, &run, 0, 100).unwrap()), ["one", "two", "oops", "three"]);
+
+        // next resumes right after the last record returned, and at the end past it
+        let page = read_lines(&dir, &all, 1, 2).unwrap();
+        assert_eq!(messages(&read_page(&dir, &all, page.next, u64::MAX).unwrap()), ["oops", "three"]);
+        let end = read_lines(&dir, &all, 10, 100).unwrap();
+        assert!(end.records.is_empty());
+        assert_eq!((end.offset, end.next), (log_end(&dir), log_end(&dir)));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn read_lines_of_a_missing_log_is_empty() {
+        let page = read_lines(&test_dir("missing"), &LogFilter::default(), -100, 100).unwrap();
+        assert!(page.records.is_empty());
+        assert_eq!((page.offset, page.next), (0, 0));
+    }
+
+    #[test]
+    fn segments_rotate_and_keep_offsets() {
+        let dir = test_dir("rotate");
+        let big = "x".repeat(SEGMENT_SIZE as usize * 2 / 3);
+        let mut offsets = Vec::new();
+        for i in 0..MAX_SEGMENTS + 2 {
+            offsets.push(write(&dir, LogStream::Stdout, LogPhase::Run, &[&format!("{} {}", i, big)]));
+        }
+
+        // Every write went past the segment size, so each started a segment of its own
+        let kept = segments(&dir);
+        assert_eq!(kept.len(), MAX_SEGMENTS);
+        assert_eq!(kept.iter().map(|(start, _)| *start).collect::<Vec<_>>(), offsets[2..]);
+        assert!(kept.iter().all(|(_, path)| fs::metadata(path).unwrap().len() <= SEGMENT_SIZE));
+        let line = offsets[1] - offsets[0];
+        assert!(offsets.windows(2).all(|pair| pair[1] - pair[0] == line));
+        assert_eq!(log_end(&dir), offsets[0] + line * offsets.len() as u64);
+
+        // Reading from before what is left starts at the oldest segment still there
+        let page = read_lines(&dir, &LogFilter::default(), 0, 100).unwrap();
+        assert_eq!(page.offset, offsets[2]);
+        let first: Vec<String> = messages(&page).iter().map(|message| message.split(' ').next().unwrap().to_string()).collect();
+        assert_eq!(first, (2..MAX_SEGMENTS + 2).map(|i| i.to_string()).collect::<Vec<_>>());
+        let chunk = read_range(&dir, 0, 10).unwrap();
+        assert_eq!(chunk.offset, offsets[2]);
+        let chunk = read_range(&dir, offsets[4] + 5, 10).unwrap();
+        assert_eq!(chunk.offset, offsets[4] + 5);
+        assert_eq!(chunk.text.len(), 10);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn follow_sends_the_backlog_then_new_lines_until_the_reader_leaves() {
+        let state = crate::db::test_state("follow").await;
+        let dir = test_dir("follow");
+        write(&dir, LogStream::Stdout, LogPhase::Run, &["one"]);
+        let all = LogFilter::default();
+        let publish = |message: &str| {
+            let text = format_lines(LogStream::Stdout, LogPhase::Run, LogLevel::Info, message);
+            let offset = write_segment(&dir, &text).unwrap();
+            state.logs.publish(7, LogChunk { offset, text });
+        };
+
+        let mut rx = follow(&state, dir.clone(), 7, 0).await.unwrap();
+        assert_eq!(messages(&LogPage::from_chunk(&rx.recv().await.unwrap(), &all)), ["one"]);
+        publish("two");
+        assert_eq!(messages(&LogPage::from_chunk(&rx.recv().await.unwrap(), &all)), ["two"]);
+
+        // Starting at the end skips what is already there
+        let mut tail = follow(&state, dir.clone(), 7, log_end(&dir)).await.unwrap();
+        publish("three");
+        assert_eq!(messages(&LogPage::from_chunk(&tail.recv().await.unwrap(), &all)), ["three"]);
+        assert_eq!(messages(&LogPage::from_chunk(&rx.recv().await.unwrap(), &all)), ["three"]);
+
+        // With nothing more written, the followers still go once their readers do
+        drop(rx);
+        drop(tail);
+        tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+        assert_eq!(state.logs.channels.lock().unwrap()[&7].receiver_count(), 0);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn append_writes_the_log_files_and_keeps_only_a_summary_in_the_database() {
+        let state = crate::db::test_state("append").await;
+        let conn = state.db.connect().unwrap();
+        conn.execute("INSERT INTO projects (name, git_repo, run_cmd) VALUES ('app', 'https://example.com/app.git', 'true')", ()).await.unwrap();
+        conn.execute("INSERT INTO deployments (project_id, status, logs) VALUES (?, 0, '')", [conn.last_insert_rowid()]).await.unwrap();
+        let deployment_id = conn.last_insert_rowid();
+        let name = format!("edgezone-test-append-{}", std::process::id());
+        let log = DeploymentLog::new(&conn, &state.logs, &name, deployment_id);
+
+        log.append(LogStream::System, LogPhase::Build, LogLevel::Info, "Building").await.unwrap();
+        log.append(LogStream::Stdout, LogPhase::Build, LogLevel::Info, "compiled 1\ncompiled 2").await.unwrap();
+        log.append(LogStream::System, LogPhase::Build, LogLevel::Error, "Build failed").await.unwrap();
+
+        let page = read_lines(&log_dir(&name, deployment_id), &LogFilter::default(), 0, 100).unwrap();
+        assert_eq!(messages(&page), ["Building", "compiled 1", "compiled 2", "Build failed"]);
+        let summary: String = {
+            let mut rows = conn.query("SELECT logs FROM deployments WHERE id = ?", [deployment_id]).await.unwrap();
+            rows.next().await.unwrap().unwrap().get(0).unwrap()
+        };
+        assert_eq!(summary, "Building\nError: Build failed\n");
+
+        // The summary keeps the end of what was said
+        log.append(LogStream::System, LogPhase::Run, LogLevel::Info, &"x".repeat(SUMMARY_SIZE as usize)).await.unwrap();
+        let summary: String = {
+            let mut rows = conn.query("SELECT logs FROM deployments WHERE id = ?", [deployment_id]).await.unwrap();
+            rows.next().await.unwrap().unwrap().get(0).unwrap()
+        };
+        assert_eq!(summary.len(), SUMMARY_SIZE as usize);
+        assert!(summary.ends_with("xx\n"));
+        fs::remove_dir_all(format!("projects/{}", name)).unwrap();
+    }
+
+    #[test]
+    fn records_are_timestamped_json_lines_with_their_metadata() {
+        let text = format_lines(LogStream::Stderr, LogPhase::Install, LogLevel::Warn, "first\r\nsecond \"quoted\"\n");
+        let lines: Vec<serde_json::Value> = text.lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["stream"], "stderr");
+        assert_eq!(lines[0]["phase"], "install");
+        assert_eq!(lines[0]["level"], "warn");
+        assert_eq!(lines[0]["message"], "first");
+        assert_eq!(lines[1]["message"], "second \"quoted\"");
+        let ts = lines[0]["ts"].as_str().unwrap();
+        assert!(chrono::DateTime::parse_from_rfc3339(ts).is_ok(), "{}", ts);
+        assert!(ts.ends_with('Z'));
+
+        // Offsets point at the start of each record, a line cut off at either end is left out
+        let chunk = LogChunk { offset: 100, text: format!("half a record\n{}{{\"ts\":", text) };
+        let records = parse_records(&chunk);
+        let first = 100 + "half a record\n".len() as u64;
+        assert_eq!(records.iter().map(|(offset, _)| *offset).collect::<Vec<_>>(), [first, first + text.find('\n').unwrap() as u64 + 1]);
+
+        let stderr = LogFilter { stream: Some(LogStream::Stderr), ..Default::default() };
+        let run = LogFilter { phase: Some(LogPhase::Run), ..Default::default() };
+        assert_eq!(messages(&LogPage::from_chunk(&chunk, &stderr)), ["first", "second \"quoted\""]);
+        assert!(LogPage::from_chunk(&chunk, &run).records.is_empty());
+        assert_eq!(LogPage::from_chunk(&chunk, &stderr).to_json_lines(), text);
+    }
+}
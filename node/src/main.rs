@@ -1,31 +1,97 @@
 use axum::{
+    middleware,
     routing::{delete, get, post, put}, Router,
 };
 use clap::Parser;
 
+mod auth;
+mod cgroup;
 mod endpoints;
 mod db;
+mod env_vars;
 mod error;
+mod events;
+mod logs;
+mod metrics;
+mod ports;
+mod proxy;
+mod sandbox;
+mod secrets;
+mod update;
 
-async fn auto_deploy(state: &db::AppState) {
+const JANITOR_INTERVAL: std::time::Duration = std::time::Duration::from_secs(60 * 60);
+
+/// Brings back the deployments that were running when the node last went
+/// down, reusing their processes or builds wherever possible. The ones it
+/// was in the middle of setting up are failed.
+async fn reconcile(state: &db::AppState) {
     let conn = state.db.connect().unwrap();
+
+    // Deployments that were still being set up don't pick up where they were
+    let mut deployments = conn.query(
+        "SELECT id FROM deployments WHERE status IN (?, ?, ?, ?)",
+        (endpoints::STATUS_PENDING, endpoints::STATUS_INSTALLING, endpoints::STATUS_BUILDING, endpoints::STATUS_STARTING)
+    ).await.unwrap();
+    let mut interrupted = Vec::new();
+    while let Ok(Some(row)) = deployments.next().await {
+        interrupted.push(row.get::<i64>(0).unwrap());
+    }
+    drop(deployments);
+
+    for deployment_id in interrupted {
+        if let Err(e) = endpoints::fail_interrupted(state, deployment_id).await {
+            eprintln!("Failed to clean up interrupted deployment {}: {:?}", deployment_id, e);
+        }
+    }
     
-    let mut projects = conn.query(
-        "SELECT p.id FROM projects p 
-         INNER JOIN deployments d ON d.project_id = p.id 
-         WHERE d.status = 3 
-         GROUP BY p.id", 
+    // Projects that were stopped on purpose stay that way
+    let mut deployments = conn.query(
+        "SELECT d.project_id, d.id FROM deployments d 
+         INNER JOIN projects p ON p.id = d.project_id 
+         WHERE d.status = 3 AND p.stopped = 0", 
         ()
     ).await.unwrap();
 
-    while let Ok(Some(row)) = projects.next().await {
-        let project_id: i32 = row.get(0).unwrap();
-        
-        if let Err(e) = endpoints::deploy(
-            axum::extract::State(state.clone()),
-            axum::extract::Path(project_id.to_string())
-        ).await {
-            eprintln!("Failed to auto-deploy project {}: {:?}", project_id, e);
+    let mut running = Vec::new();
+    while let Ok(Some(row)) = deployments.next().await {
+        running.push((row.get::<i32>(0).unwrap(), row.get::<i64>(1).unwrap()));
+    }
+    drop(deployments);
+
+    for (project_id, deployment_id) in running {
+        let state = state.clone();
+        tokio::spawn(async move {
+            if let Err(e) = endpoints::resume_deployment(&state, project_id, deployment_id).await {
+                eprintln!("Failed to resume deployment {} of project {}: {:?}", deployment_id, project_id, e);
+            }
+        });
+    }
+}
+
+/// Prunes old deployments of every project, and old webhook deliveries, once
+/// per JANITOR_INTERVAL.
+async fn janitor(state: db::AppState) {
+    // Leave the first run until after startup, when the deployments are back up
+    let start = tokio::time::Instant::now() + JANITOR_INTERVAL;
+    let mut interval = tokio::time::interval_at(start, JANITOR_INTERVAL);
+    loop {
+        interval.tick().await;
+        let Ok(conn) = state.db.connect() else { continue };
+        let Ok(mut projects) = conn.query("SELECT id FROM projects", ()).await else { continue };
+        let mut ids = Vec::new();
+        while let Ok(Some(row)) = projects.next().await {
+            if let Ok(id) = row.get::<i32>(0) {
+                ids.push(id);
+            }
+        }
+        drop(projects);
+        for project_id in ids {
+            if let Err(e) = endpoints::prune_deployments(&conn, project_id).await {
+                eprintln!("Failed to prune project {}: {:?}", project_id, e);
+            }
+        }
+        if let Err(e) = events::prune_deliveries(&conn).await {
+            eprintln!("Failed to prune webhook deliveries: {:?}", e);
         }
     }
 }
@@ -35,6 +101,33 @@ async fn auto_deploy(state: &db::AppState) {
 struct Cli {
     #[arg(short, long, default_value_t = 3000)]
     port: u16,
+    /// Shared secret the master must send as a bearer token (generated on first start if omitted)
+    #[arg(long, env = "EDGEZONE_TOKEN")]
+    token: Option<String>,
+    /// Base URL or local directory that releases are downloaded from
+    #[arg(long, env = "EDGEZONE_UPDATE_URL")]
+    update_url: Option<String>,
+    /// Hex ed25519 public key releases must be signed with (overrides the one pinned at build time)
+    #[arg(long, env = "EDGEZONE_UPDATE_KEY")]
+    update_key: Option<String>,
+    /// Route requests for project domains to their running deployments
+    #[arg(long, env = "EDGEZONE_PROXY")]
+    proxy: bool,
+    /// Address the proxy serves plain HTTP on
+    #[arg(long, env = "EDGEZONE_PROXY_HTTP", default_value = "0.0.0.0:80")]
+    proxy_http: std::net::SocketAddr,
+    /// Address the proxy serves HTTPS on, when there are certificates
+    #[arg(long, env = "EDGEZONE_PROXY_HTTPS", default_value = "0.0.0.0:443")]
+    proxy_https: std::net::SocketAddr,
+    /// Directory of <domain>.pem and <domain>.key files for HTTPS
+    #[arg(long, env = "EDGEZONE_PROXY_CERTS", default_value = "certs")]
+    proxy_certs: std::path::PathBuf,
+    /// Ports handed to deployments as PORT, as first-last
+    #[arg(long, env = "EDGEZONE_PORT_RANGE", default_value = ports::DEFAULT_RANGE)]
+    port_range: ports::PortRange,
+    /// Run as the rollback watchdog of the node with this pid after an update
+    #[arg(long, hide = true)]
+    watch_update: Option<i32>,
 }
 
 #[tokio::main]
@@ -42,14 +135,40 @@ async fn main() {
     tracing_subscriber::fmt::init();
     
     let cli = Cli::parse();
-    let state = db::init_db().await;
+    let token = auth::load_or_create_token(cli.token);
+    if let Some(pid) = cli.watch_update {
+        update::watch(pid, cli.port, &token).await;
+        return;
+    }
+    let state = db::init_db(update::Updater::new(cli.update_url, cli.update_key)).await;
+    cgroup::init();
+    ports::init(cli.port_range);
+    secrets::init();
     
-    auto_deploy(&state).await;
+    reconcile(&state).await;
+    if cli.proxy {
+        state.routes.refresh(&state.db.connect().unwrap()).await;
+        tokio::spawn(proxy::serve_http(state.routes.clone(), cli.proxy_http));
+        if cli.proxy_certs.is_dir() {
+            tokio::spawn(proxy::serve_https(state.routes.clone(), cli.proxy_https, cli.proxy_certs));
+        } else {
+            println!("No certificates in {}, the proxy only serves plain HTTP", cli.proxy_certs.display());
+        }
+    }
+    tokio::spawn(janitor(state.clone()));
+    tokio::spawn(metrics::sampler(state.clone()));
+    tokio::spawn(events::deliver(state.clone()));
 
-    let app = Router::new()
-        .route("/", get(root))
+    let api = Router::new()
         .route("/update", post(endpoints::update))
+        .route("/update", get(endpoints::update_status))
         .route("/info", get(endpoints::info))
+        .route("/metrics", get(endpoints::prometheus_metrics))
+        .route("/metrics/node", get(endpoints::node_metrics))
+        .route("/webhooks", get(endpoints::list_webhooks))
+        .route("/webhooks", post(endpoints::create_webhook))
+        .route("/webhooks/{id}", delete(endpoints::delete_webhook))
+        .route("/webhooks/{id}/deliveries", get(endpoints::list_deliveries))
         // Project routes
         .route("/projects", post(endpoints::create_project))
         .route("/projects", get(endpoints::list_projects))
@@ -59,8 +178,32 @@ async fn main() {
         // Deployment routes
         .route("/projects/{id}/deploy", post(endpoints::deploy))
         .route("/projects/{id}/deployments", get(endpoints::list_deployments))
+        .route("/projects/{id}/prune", post(endpoints::prune_project))
+        .route("/projects/{id}/stop", post(endpoints::stop_project))
+        .route("/projects/{id}/start", post(endpoints::start_project))
+        .route("/projects/{id}/env", get(endpoints::get_env))
+        .route("/projects/{id}/env", post(endpoints::import_env))
+        .route("/projects/{id}/env/{key}", get(endpoints::get_env_var))
+        .route("/projects/{id}/env/{key}", put(endpoints::put_env_var))
+        .route("/projects/{id}/env/{key}", delete(endpoints::delete_env_var))
+        .route("/projects/{id}/secrets", get(endpoints::list_secrets))
+        .route("/projects/{id}/secrets/{key}", put(endpoints::put_secret))
+        .route("/projects/{id}/secrets/{key}", delete(endpoints::delete_secret))
         .route("/projects/{project_id}/deployments/{deployment_id}", get(endpoints::get_deployment))
+        .route("/projects/{project_id}/deployments/{deployment_id}/rollback", post(endpoints::rollback_deployment))
+        .route("/projects/{project_id}/deployments/{deployment_id}/pin", post(endpoints::pin_deployment))
+        .route("/projects/{project_id}/deployments/{deployment_id}/pin", delete(endpoints::unpin_deployment))
         .route("/projects/{project_id}/deployments/{deployment_id}/restart", post(endpoints::restart_deployment))
+        .route("/projects/{project_id}/deployments/{deployment_id}/restarts", get(endpoints::list_restarts))
+        .route("/projects/{project_id}/deployments/{deployment_id}/metrics", get(endpoints::deployment_metrics))
+        .route("/projects/{project_id}/deployments/{deployment_id}/logs", get(endpoints::read_logs))
+        .route("/projects/{project_id}/deployments/{deployment_id}/logs/stream", get(endpoints::stream_logs))
+        .route("/projects/{project_id}/deployments/{deployment_id}/logs/ws", get(endpoints::ws_logs))
+        .route_layer(middleware::from_fn_with_state(token, auth::require_token));
+
+    let app = Router::new()
+        .route("/", get(root))
+        .merge(api)
         .with_state(state);
 
     let listener = tokio::net::TcpListener::bind(format!("0.0.0.0:{}", cli.port)).await.unwrap();
@@ -0,0 +1,313 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt::Write,
+    fs,
+    sync::{Arc, Mutex},
+};
+use nix::{sys::statvfs::statvfs, unistd::{sysconf, SysconfVar}};
+use serde_derive::Serialize;
+
+use crate::db::AppState;
+use crate::endpoints::{running_pgid, STATUS_RUNNING, STATUS_STARTING};
+
+const SAMPLE_INTERVAL: std::time::Duration = std::time::Duration::from_secs(15);
+/// Samples kept per series, an hour's worth.
+const HISTORY_LEN: usize = 240;
+/// Where a Raspberry Pi, and most other boards, report their SoC temperature.
+const THERMAL_ZONE: &str = "/sys/class/thermal/thermal_zone0/temp";
+
+/// One look at the machine as a whole.
+#[derive(Clone, Serialize)]
+pub struct NodeSample {
+    pub timestamp: i64,
+    pub load1: f64,
+    pub load5: f64,
+    pub load15: f64,
+    pub memory_total: u64,
+    pub memory_available: u64,
+    /// Of the filesystem holding `projects/`.
+    pub disk_total: u64,
+    pub disk_free: u64,
+    /// In degrees Celsius, where the board reports one.
+    pub temperature: Option<f64>,
+}
+
+/// One look at the process group of a deployment's run command.
+#[derive(Clone, Serialize)]
+pub struct ProcessSample {
+    pub timestamp: i64,
+    pub processes: u32,
+    /// Since the previous sample, 100 being one full CPU.
+    pub cpu_percent: f64,
+    pub cpu_seconds: f64,
+    pub rss: u64,
+    pub fds: u64,
+    /// Seconds since the group leader was started.
+    pub uptime: u64,
+}
+
+/// Name, type, help text and value of a per-deployment Prometheus metric.
+type Series = (&'static str, &'static str, &'static str, fn(&ProcessSample) -> f64);
+
+struct DeploymentMetrics {
+    project_id: i32,
+    project: String,
+    deployment_id: i64,
+    samples: VecDeque<ProcessSample>,
+}
+
+#[derive(Default)]
+struct History {
+    node: VecDeque<NodeSample>,
+    deployments: HashMap<i64, DeploymentMetrics>,
+}
+
+/// Recent samples of the node and its running deployments, kept in memory only.
+#[derive(Clone, Default)]
+pub struct Metrics {
+    history: Arc<Mutex<History>>,
+}
+
+impl Metrics {
+    pub fn node(&self, since: Option<i64>) -> Vec<NodeSample> {
+        let history = self.history.lock().unwrap();
+        history
+            .node
+            .iter()
+            .filter(|s| since.is_none_or(|since| s.timestamp > since))
+            .cloned()
+            .collect()
+    }
+
+    pub fn deployment(&self, deployment_id: i64, since: Option<i64>) -> Vec<ProcessSample> {
+        let history = self.history.lock().unwrap();
+        let Some(metrics) = history.deployments.get(&deployment_id) else { return Vec::new() };
+        metrics
+            .samples
+            .iter()
+            .filter(|s| since.is_none_or(|since| s.timestamp > since))
+            .cloned()
+            .collect()
+    }
+
+    /// The latest samples in the Prometheus text exposition format.
+    pub fn prometheus(&self) -> String {
+        let history = self.history.lock().unwrap();
+        let mut out = String::new();
+        if let Some(node) = history.node.back() {
+            let gauges = [
+                ("edgezone_node_load1", "1 minute load average.", node.load1),
+                ("edgezone_node_load5", "5 minute load average.", node.load5),
+                ("edgezone_node_load15", "15 minute load average.", node.load15),
+                ("edgezone_node_memory_total_bytes", "Total memory.", node.memory_total as f64),
+                ("edgezone_node_memory_available_bytes", "Memory available to new processes.", node.memory_available as f64),
+                ("edgezone_node_disk_total_bytes", "Size of the filesystem holding the projects.", node.disk_total as f64),
+                ("edgezone_node_disk_free_bytes", "Free space on the filesystem holding the projects.", node.disk_free as f64),
+            ];
+            for (name, help, value) in gauges {
+                let _ = writeln!(out, "# HELP {} {}\n# TYPE {} gauge\n{} {}", name, help, name, name, value);
+            }
+            if let Some(temperature) = node.temperature {
+                let name = "edgezone_node_temperature_celsius";
+                let _ = writeln!(out, "# HELP {} SoC temperature.\n# TYPE {} gauge\n{} {}", name, name, name, temperature);
+            }
+        }
+
+        let mut deployments: Vec<&DeploymentMetrics> = history
+            .deployments
+            .values()
+            .filter(|d| !d.samples.is_empty())
+            .collect();
+        deployments.sort_by_key(|d| d.deployment_id);
+        let series: [Series; 5] = [
+            ("edgezone_deployment_cpu_seconds_total", "counter", "CPU time used by the run process group.", |s| s.cpu_seconds),
+            ("edgezone_deployment_resident_memory_bytes", "gauge", "Resident memory of the run process group.", |s| s.rss as f64),
+            ("edgezone_deployment_open_fds", "gauge", "Open file descriptors of the run process group.", |s| s.fds as f64),
+            ("edgezone_deployment_processes", "gauge", "Processes in the run process group.", |s| s.processes as f64),
+            ("edgezone_deployment_uptime_seconds", "gauge", "Time since the run process was started.", |s| s.uptime as f64),
+        ];
+        for (name, kind, help, value) in series {
+            if deployments.is_empty() {
+                break;
+            }
+            let _ = writeln!(out, "# HELP {} {}\n# TYPE {} {}", name, help, name, kind);
+            for deployment in &deployments {
+                let Some(sample) = deployment.samples.back() else { continue };
+                let _ = writeln!(
+                    out,
+                    "{}{{project=\"{}\",project_id=\"{}\",deployment=\"{}\"}} {}",
+                    name,
+                    escape_label(&deployment.project),
+                    deployment.project_id,
+                    deployment.deployment_id,
+                    value(sample)
+                );
+            }
+        }
+        out
+    }
+
+    fn record(&self, node: Option<NodeSample>, processes: Vec<(i32, String, i64, ProcessSample)>) {
+        let mut history = self.history.lock().unwrap();
+        if let Some(node) = node {
+            push(&mut history.node, node);
+        }
+        let now = chrono::Utc::now().timestamp();
+        for (project_id, project, deployment_id, sample) in processes {
+            let metrics = history.deployments.entry(deployment_id).or_insert_with(|| DeploymentMetrics {
+                project_id,
+                project: project.clone(),
+                deployment_id,
+                samples: VecDeque::new(),
+            });
+            metrics.project = project;
+            push(&mut metrics.samples, sample);
+        }
+        // Deployments that stopped are forgotten once their history ran out
+        let window = (SAMPLE_INTERVAL.as_secs() as usize * HISTORY_LEN) as i64;
+        history
+            .deployments
+            .retain(|_, d| d.samples.back().is_some_and(|s| now - s.timestamp < window));
+    }
+}
+
+fn push<T>(samples: &mut VecDeque<T>, sample: T) {
+    if samples.len() >= HISTORY_LEN {
+        samples.pop_front();
+    }
+    samples.push_back(sample);
+}
+
+fn escape_label(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+/// Samples the node and its running deployments every SAMPLE_INTERVAL.
+pub async fn sampler(state: AppState) {
+    let ticks = sysconf(SysconfVar::CLK_TCK).ok().flatten().unwrap_or(100) as f64;
+    // CPU time of each deployment at the previous sample, for the percentage
+    let mut previous: HashMap<i64, (f64, std::time::Instant)> = HashMap::new();
+    let mut interval = tokio::time::interval(SAMPLE_INTERVAL);
+    loop {
+        interval.tick().await;
+        let deployments = running_deployments(&state).await.unwrap_or_default();
+
+        let mut processes = Vec::new();
+        let mut seen = HashMap::new();
+        for (project_id, project, deployment_id) in deployments {
+            let Some(pgid) = running_pgid(&format!("projects/{}/{}", project, deployment_id)) else { continue };
+            let Some(mut sample) = sample_group(pgid, ticks) else { continue };
+            let now = std::time::Instant::now();
+            if let Some((cpu_seconds, at)) = previous.get(&deployment_id) {
+                let elapsed = now.duration_since(*at).as_secs_f64();
+                if elapsed > 0.0 && sample.cpu_seconds >= *cpu_seconds {
+                    sample.cpu_percent = (sample.cpu_seconds - cpu_seconds) / elapsed * 100.0;
+                }
+            }
+            seen.insert(deployment_id, (sample.cpu_seconds, now));
+            processes.push((project_id, project, deployment_id, sample));
+        }
+        previous = seen;
+
+        state.metrics.record(sample_node(), processes);
+    }
+}
+
+async fn running_deployments(state: &AppState) -> Result<Vec<(i32, String, i64)>, libsql::Error> {
+    let conn = state.db.connect()?;
+    let mut rows = conn
+        .query(
+            "SELECT p.id, p.name, d.id FROM deployments d
+             INNER JOIN projects p ON p.id = d.project_id
+             WHERE d.status IN (?, ?)",
+            (STATUS_RUNNING, STATUS_STARTING),
+        )
+        .await?;
+    let mut deployments = Vec::new();
+    while let Some(row) = rows.next().await? {
+        deployments.push((row.get::<i32>(0)?, row.get::<String>(1)?, row.get::<i64>(2)?));
+    }
+    Ok(deployments)
+}
+
+// statvfs counts are narrower than u64 on 32-bit boards
+#[allow(clippy::useless_conversion)]
+fn sample_node() -> Option<NodeSample> {
+    let loadavg = fs::read_to_string("/proc/loadavg").ok()?;
+    let mut load = loadavg.split_whitespace().map(|l| l.parse::<f64>().unwrap_or(0.0));
+    let meminfo = fs::read_to_string("/proc/meminfo").ok()?;
+    // Values in /proc/meminfo are in KiB
+    let memory = |key: &str| -> u64 {
+        meminfo
+            .lines()
+            .find_map(|line| line.strip_prefix(key)?.strip_prefix(':'))
+            .and_then(|value| value.split_whitespace().next()?.parse::<u64>().ok())
+            .unwrap_or(0)
+            * 1024
+    };
+    let (disk_total, disk_free) = statvfs("projects")
+        .or_else(|_| statvfs("."))
+        .map(|s| {
+            let fragment = u64::from(s.fragment_size());
+            (u64::from(s.blocks()) * fragment, u64::from(s.blocks_available()) * fragment)
+        })
+        .unwrap_or((0, 0));
+    let temperature = fs::read_to_string(THERMAL_ZONE)
+        .ok()
+        .and_then(|t| t.trim().parse::<f64>().ok())
+        .map(|millidegrees| millidegrees / 1000.0);
+
+    Some(NodeSample {
+        timestamp: chrono::Utc::now().timestamp(),
+        load1: load.next().unwrap_or(0.0),
+        load5: load.next().unwrap_or(0.0),
+        load15: load.next().unwrap_or(0.0),
+        memory_total: memory("MemTotal"),
+        memory_available: memory("MemAvailable"),
+        disk_total,
+        disk_free,
+        temperature,
+    })
+}
+
+/// Adds up every process in the process group `pgid`. None when the group is gone.
+fn sample_group(pgid: i32, ticks: f64) -> Option<ProcessSample> {
+    let page_size = sysconf(SysconfVar::PAGE_SIZE).ok().flatten().unwrap_or(4096) as u64;
+    let boot_uptime: f64 = fs::read_to_string("/proc/uptime")
+        .ok()?
+        .split_whitespace()
+        .next()?
+        .parse()
+        .ok()?;
+
+    let mut sample = ProcessSample {
+        timestamp: chrono::Utc::now().timestamp(),
+        processes: 0,
+        cpu_percent: 0.0,
+        cpu_seconds: 0.0,
+        rss: 0,
+        fds: 0,
+        uptime: 0,
+    };
+    for entry in fs::read_dir("/proc").ok()?.flatten() {
+        let name = entry.file_name();
+        let Some(pid) = name.to_str().and_then(|n| n.parse::<i32>().ok()) else { continue };
+        let Ok(stat) = fs::read_to_string(format!("/proc/{}/stat", pid)) else { continue };
+        // The command name can hold spaces and parentheses, the fields after it can't
+        let Some((_, fields)) = stat.rsplit_once(") ") else { continue };
+        let fields: Vec<&str> = fields.split_whitespace().collect();
+        // Numbered from the state field, which is field 3 in proc(5)
+        let field = |n: usize| fields.get(n - 3).and_then(|f| f.parse::<u64>().ok()).unwrap_or(0);
+        if field(5) as i32 != pgid {
+            continue;
+        }
+        sample.processes += 1;
+        sample.cpu_seconds += (field(14) + field(15)) as f64 / ticks;
+        sample.rss += field(24) * page_size;
+        sample.fds += fs::read_dir(format!("/proc/{}/fd", pid)).map(|fds| fds.count() as u64).unwrap_or(0);
+        if pid == pgid {
+            sample.uptime = (boot_uptime - field(22) as f64 / ticks).max(0.0) as u64;
+        }
+    }
+    (sample.processes > 0).then_some(sample)
+}
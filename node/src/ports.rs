@@ -0,0 +1,94 @@
+use std::{net::TcpListener, ops::RangeInclusive, str::FromStr, sync::OnceLock};
+
+use crate::endpoints::{STATUS_CRASHLOOP, STATUS_FAILED, STATUS_STOPPED};
+use crate::error::AppError;
+
+pub const DEFAULT_RANGE: &str = "20000-29999";
+/// The ports deployments are given, set once at startup.
+static RANGE: OnceLock<RangeInclusive<u16>> = OnceLock::new();
+/// Held from picking a port until it is recorded, so that two deployments
+/// starting at once don't end up with the same one.
+static ASSIGN_LOCK: tokio::sync::Mutex<()> = tokio::sync::Mutex::const_new(());
+
+/// A `first-last` range of ports, as given on the command line.
+#[derive(Clone, Debug)]
+pub struct PortRange(RangeInclusive<u16>);
+
+impl FromStr for PortRange {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (first, last) = s.split_once('-').ok_or("expected a range like 20000-29999")?;
+        let first = first.trim().parse::<u16>().map_err(|e| e.to_string())?;
+        let last = last.trim().parse::<u16>().map_err(|e| e.to_string())?;
+        if first == 0 || first > last {
+            return Err("the range must be non-empty and start above 0".to_string());
+        }
+        Ok(PortRange(first..=last))
+    }
+}
+
+pub fn init(range: PortRange) {
+    let _ = RANGE.set(range.0);
+}
+
+/// Whether nothing listens on `port` on any address right now.
+pub fn is_free(port: u16) -> bool {
+    TcpListener::bind(("0.0.0.0", port)).is_ok()
+}
+
+/// Gives a deployment its port: the one it already has when that is still
+/// free, otherwise the first free one in the range that no other live
+/// deployment holds. The port is recorded on the deployment.
+pub async fn assign(conn: &libsql::Connection, deployment_id: i64) -> Result<u16, AppError> {
+    let range = RANGE
+        .get()
+        .cloned()
+        .unwrap_or_else(|| DEFAULT_RANGE.parse::<PortRange>().unwrap().0);
+    let _guard = ASSIGN_LOCK.lock().await;
+
+    let (current, taken) = {
+        let mut rows = conn
+            .query(
+                "SELECT id, port FROM deployments
+                 WHERE port IS NOT NULL AND (id = ? OR status NOT IN (?, ?, ?))",
+                (deployment_id, STATUS_STOPPED, STATUS_FAILED, STATUS_CRASHLOOP),
+            )
+            .await?;
+        let mut current = None;
+        let mut taken = Vec::new();
+        while let Some(row) = rows.next().await? {
+            let Ok(port) = u16::try_from(row.get::<i32>(1)?) else { continue };
+            if row.get::<i64>(0)? == deployment_id {
+                current = Some(port);
+            } else {
+                taken.push(port);
+            }
+        }
+        (current, taken)
+    };
+
+    let port = current
+        .filter(|port| !taken.contains(port) && is_free(*port))
+        .or_else(|| range.clone().find(|port| !taken.contains(port) && is_free(*port)))
+        .ok_or_else(|| AppError::Internal(format!("No free port left between {} and {}", range.start(), range.end())))?;
+    if current != Some(port) {
+        conn.execute("UPDATE deployments SET port = ? WHERE id = ?", (port, deployment_id)).await?;
+    }
+    Ok(port)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_port_ranges() {
+        assert_eq!("20000-29999".parse::<PortRange>().unwrap().0, 20000..=29999);
+        assert_eq!(" 8000 - 8000 ".parse::<PortRange>().unwrap().0, 8000..=8000);
+        assert_eq!("1-65535".parse::<PortRange>().unwrap().0, 1..=65535);
+        for range in ["", "8000", "0-100", "200-100", "8000-70000", "a-b", "-8000", "8000-"] {
+            assert!(range.parse::<PortRange>().is_err(), "{}", range);
+        }
+    }
+}
@@ -0,0 +1,361 @@
+use std::{
+    collections::HashMap,
+    convert::Infallible,
+    fs,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+};
+use axum::body::Body;
+use hyper::{
+    body::Incoming,
+    header::{self, HeaderMap, HeaderName, HeaderValue},
+    service::service_fn,
+    Request, Response, StatusCode, Uri,
+};
+use hyper_util::{
+    client::legacy::{connect::HttpConnector, Client},
+    rt::{TokioExecutor, TokioIo},
+};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_rustls::{
+    rustls::{
+        self,
+        crypto::ring::{default_provider, sign::any_supported_type},
+        server::{ClientHello, ResolvesServerCert},
+        sign::CertifiedKey,
+    },
+    TlsAcceptor,
+};
+
+use crate::endpoints::STATUS_RUNNING;
+
+/// How often certificates are read again, so renewed ones get picked up.
+const CERT_RELOAD_INTERVAL: std::time::Duration = std::time::Duration::from_secs(60 * 60);
+/// Headers that only mean something for one connection and are not passed on.
+const HOP_BY_HOP: [&str; 7] = [
+    "connection",
+    "keep-alive",
+    "proxy-connection",
+    "te",
+    "trailer",
+    "transfer-encoding",
+    "upgrade",
+];
+
+/// Which local port each domain is served from, the one of its project's
+/// running deployment.
+#[derive(Clone, Default)]
+pub struct Routes {
+    table: Arc<RwLock<HashMap<String, u16>>>,
+}
+
+impl Routes {
+    /// Rebuilds the table from the database. Called whenever a deployment
+    /// takes over or a project's domain, port or state changes.
+    pub async fn refresh(&self, conn: &libsql::Connection) {
+        match load_routes(conn).await {
+            Ok(table) => *self.table.write().unwrap() = table,
+            Err(e) => eprintln!("Failed to refresh proxy routes: {:?}", e),
+        }
+    }
+
+    fn lookup(&self, host: &str) -> Option<u16> {
+        self.table.read().unwrap().get(host).copied()
+    }
+}
+
+async fn load_routes(conn: &libsql::Connection) -> Result<HashMap<String, u16>, libsql::Error> {
+    let mut rows = conn
+        .query(
+            "SELECT p.domain, COALESCE(p.port, d.port) FROM projects p
+             INNER JOIN deployments d ON d.project_id = p.id AND d.status = ?
+             WHERE p.domain IS NOT NULL AND COALESCE(p.port, d.port) IS NOT NULL AND p.stopped = 0
+             ORDER BY d.sequence",
+            [STATUS_RUNNING],
+        )
+        .await?;
+    let mut table = HashMap::new();
+    while let Some(row) = rows.next().await? {
+        let domain = normalize_host(&row.get::<String>(0)?);
+        if let Ok(port) = u16::try_from(row.get::<i32>(1)?) {
+            table.insert(domain, port);
+        }
+    }
+    Ok(table)
+}
+
+/// Lower case and without a port or trailing dot, the way domains are looked up.
+pub fn normalize_host(host: &str) -> String {
+    let host = host.trim();
+    let host = match host.rsplit_once(':') {
+        Some((name, port)) if port.chars().all(|c| c.is_ascii_digit()) => name,
+        _ => host,
+    };
+    host.trim_end_matches('.').to_ascii_lowercase()
+}
+
+/// Whether `domain` can be routed: a host name without a port or scheme.
+pub fn valid_domain(domain: &str) -> bool {
+    !domain.is_empty()
+        && domain.len() <= 253
+        && domain
+            .split('.')
+            .all(|label| {
+                !label.is_empty()
+                    && label.len() <= 63
+                    && !label.starts_with('-')
+                    && !label.ends_with('-')
+                    && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+            })
+}
+
+/// Serves plain HTTP on `addr` until the node stops.
+pub async fn serve_http(routes: Routes, addr: SocketAddr) {
+    serve(routes, addr, None).await
+}
+
+/// Serves HTTPS on `addr` with the certificates in `cert_dir`, picked by the
+/// name the client asks for. See `CertStore` for the file layout.
+pub async fn serve_https(routes: Routes, addr: SocketAddr, cert_dir: PathBuf) {
+    let store = Arc::new(CertStore::default());
+    store.reload(&cert_dir);
+    let reloader = store.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(CERT_RELOAD_INTERVAL);
+        interval.tick().await;
+        loop {
+            interval.tick().await;
+            reloader.reload(&cert_dir);
+        }
+    });
+
+    let config = rustls::ServerConfig::builder_with_provider(Arc::new(default_provider()))
+        .with_safe_default_protocol_versions()
+        .map(|builder| builder.with_no_client_auth().with_cert_resolver(store));
+    let mut config = match config {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("Failed to set up TLS for the proxy: {}", e);
+            return;
+        }
+    };
+    config.alpn_protocols = vec![b"http/1.1".to_vec()];
+    serve(routes, addr, Some(TlsAcceptor::from(Arc::new(config)))).await
+}
+
+async fn serve(routes: Routes, addr: SocketAddr, tls: Option<TlsAcceptor>) {
+    let listener = match tokio::net::TcpListener::bind(addr).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            eprintln!("Proxy failed to listen on {}: {}", addr, e);
+            return;
+        }
+    };
+    println!("Proxy listening on {}", addr);
+    let client: Client<HttpConnector, Incoming> = Client::builder(TokioExecutor::new()).build_http();
+    loop {
+        let Ok((stream, peer)) = listener.accept().await else { continue };
+        let routes = routes.clone();
+        let client = client.clone();
+        let tls = tls.clone();
+        tokio::spawn(async move {
+            match tls {
+                Some(acceptor) => {
+                    if let Ok(stream) = acceptor.accept(stream).await {
+                        serve_connection(stream, routes, client, peer, "https").await;
+                    }
+                }
+                None => serve_connection(stream, routes, client, peer, "http").await,
+            }
+        });
+    }
+}
+
+async fn serve_connection<S>(stream: S, routes: Routes, client: Client<HttpConnector, Incoming>, peer: SocketAddr, scheme: &'static str)
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let service = service_fn(move |request| {
+        let routes = routes.clone();
+        let client = client.clone();
+        async move { Ok::<_, Infallible>(forward(request, &routes, &client, peer, scheme).await) }
+    });
+    let _ = hyper::server::conn::http1::Builder::new()
+        .serve_connection(TokioIo::new(stream), service)
+        .with_upgrades()
+        .await;
+}
+
+/// Passes a request on to the deployment serving its Host, and the response
+/// back. Upgraded connections, WebSockets mostly, are spliced through.
+async fn forward(
+    mut request: Request<Incoming>,
+    routes: &Routes,
+    client: &Client<HttpConnector, Incoming>,
+    peer: SocketAddr,
+    scheme: &'static str,
+) -> Response<Body> {
+    let host = request
+        .headers()
+        .get(header::HOST)
+        .and_then(|h| h.to_str().ok())
+        .or(request.uri().host())
+        .unwrap_or_default()
+        .to_string();
+    let Some(port) = routes.lookup(&normalize_host(&host)) else {
+        return plain(StatusCode::NOT_FOUND, format!("No project is served at {}\n", host));
+    };
+    let path = request.uri().path_and_query().map_or("/", |p| p.as_str());
+    let Ok(uri) = format!("http://127.0.0.1:{}{}", port, path).parse::<Uri>() else {
+        return plain(StatusCode::BAD_REQUEST, "Invalid request path\n".to_string());
+    };
+    *request.uri_mut() = uri;
+
+    let upgrade = request.headers().get(header::UPGRADE).cloned();
+    strip_hop_by_hop(request.headers_mut());
+    if let Some(upgrade) = &upgrade {
+        request.headers_mut().insert(header::CONNECTION, HeaderValue::from_static("upgrade"));
+        request.headers_mut().insert(header::UPGRADE, upgrade.clone());
+    }
+    let forwarded_for = match request.headers().get("x-forwarded-for").and_then(|h| h.to_str().ok()) {
+        Some(earlier) => format!("{}, {}", earlier, peer.ip()),
+        None => peer.ip().to_string(),
+    };
+    let headers = request.headers_mut();
+    for (name, value) in [("x-forwarded-for", forwarded_for), ("x-forwarded-proto", scheme.to_string()), ("x-forwarded-host", host)] {
+        if let Ok(value) = HeaderValue::from_str(&value) {
+            headers.insert(HeaderName::from_static(name), value);
+        }
+    }
+    let downstream = upgrade.is_some().then(|| hyper::upgrade::on(&mut request));
+
+    let mut response = match client.request(request).await {
+        Ok(response) => response,
+        Err(e) => return plain(StatusCode::BAD_GATEWAY, format!("The project on port {} is not responding: {}\n", port, e)),
+    };
+    if response.status() == StatusCode::SWITCHING_PROTOCOLS {
+        if let Some(downstream) = downstream {
+            let upstream = hyper::upgrade::on(&mut response);
+            tokio::spawn(async move {
+                if let (Ok(downstream), Ok(upstream)) = (downstream.await, upstream.await) {
+                    let _ = tokio::io::copy_bidirectional(&mut TokioIo::new(downstream), &mut TokioIo::new(upstream)).await;
+                }
+            });
+        }
+    } else {
+        strip_hop_by_hop(response.headers_mut());
+    }
+    response.map(Body::new)
+}
+
+fn strip_hop_by_hop(headers: &mut HeaderMap) {
+    // Connection can name further headers that are only meant for this hop
+    let named: Vec<String> = headers
+        .get_all(header::CONNECTION)
+        .iter()
+        .filter_map(|h| h.to_str().ok())
+        .flat_map(|h| h.split(',').map(|name| name.trim().to_ascii_lowercase()))
+        .collect();
+    for name in HOP_BY_HOP.iter().copied().chain(named.iter().map(String::as_str)) {
+        headers.remove(name);
+    }
+}
+
+fn plain(status: StatusCode, message: String) -> Response<Body> {
+    let mut response = Response::new(Body::from(message));
+    *response.status_mut() = status;
+    response
+        .headers_mut()
+        .insert(header::CONTENT_TYPE, HeaderValue::from_static("text/plain; charset=utf-8"));
+    response
+}
+
+/// Certificates by domain, read from `<domain>.pem` (the chain) and
+/// `<domain>.key` files. `*.<domain>` covers subdomains and `default`
+/// anything without a certificate of its own.
+#[derive(Debug, Default)]
+struct CertStore {
+    certs: RwLock<HashMap<String, Arc<CertifiedKey>>>,
+}
+
+impl CertStore {
+    fn reload(&self, dir: &Path) {
+        let entries = match fs::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(e) => {
+                eprintln!("Failed to read proxy certificates from {}: {}", dir.display(), e);
+                return;
+            }
+        };
+        let mut certs = HashMap::new();
+        for entry in entries.flatten() {
+            let path = entry.path();
+            if path.extension().is_none_or(|ext| ext != "pem") {
+                continue;
+            }
+            let Some(name) = path.file_stem().and_then(|n| n.to_str()) else { continue };
+            match load_cert(&path, &path.with_extension("key")) {
+                Ok(cert) => {
+                    certs.insert(name.to_ascii_lowercase(), Arc::new(cert));
+                }
+                Err(e) => eprintln!("Skipping proxy certificate {}: {}", path.display(), e),
+            }
+        }
+        *self.certs.write().unwrap() = certs;
+    }
+}
+
+impl ResolvesServerCert for CertStore {
+    fn resolve(&self, hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        let certs = self.certs.read().unwrap();
+        let name = hello.server_name().map(normalize_host).unwrap_or_default();
+        let wildcard = name.split_once('.').map(|(_, parent)| format!("*.{}", parent));
+        certs
+            .get(&name)
+            .or_else(|| wildcard.and_then(|w| certs.get(&w)))
+            .or_else(|| certs.get("default"))
+            .cloned()
+    }
+}
+
+fn load_cert(cert: &Path, key: &Path) -> Result<CertifiedKey, String> {
+    let chain = rustls_pemfile::certs(&mut fs::read(cert).map_err(|e| e.to_string())?.as_slice())
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| e.to_string())?;
+    if chain.is_empty() {
+        return Err("no certificates in it".to_string());
+    }
+    let key = rustls_pemfile::private_key(&mut fs::read(key).map_err(|e| e.to_string())?.as_slice())
+        .map_err(|e| e.to_string())?
+        .ok_or_else(|| "no private key next to it".to_string())?;
+    let key = any_supported_type(&key).map_err(|e| e.to_string())?;
+    Ok(CertifiedKey::new(chain, key))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalizes_hosts() {
+        assert_eq!(normalize_host("Example.COM"), "example.com");
+        assert_eq!(normalize_host("example.com:8080"), "example.com");
+        assert_eq!(normalize_host("example.com.:443"), "example.com");
+        assert_eq!(normalize_host(" app.example.com "), "app.example.com");
+        assert_eq!(normalize_host("[::1]:80"), "[::1]");
+        assert_eq!(normalize_host("example.com:http"), "example.com:http");
+    }
+
+    #[test]
+    fn validates_domains() {
+        for domain in ["example.com", "a.b-c.example", "localhost", "xn--bcher-kva.example", "123.example"] {
+            assert!(valid_domain(domain), "{}", domain);
+        }
+        let long_label = format!("{}.com", "a".repeat(64));
+        let too_long = format!("{}com", "abcdefghi.".repeat(26));
+        for domain in ["", "example..com", ".example.com", "example.com:80", "http://example.com", "exa mple.com", "-a.com", "a-.com", "*.example.com", &long_label, &too_long] {
+            assert!(!valid_domain(domain), "{}", domain);
+        }
+    }
+}
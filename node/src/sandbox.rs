@@ -0,0 +1,191 @@
+use std::{
+    ffi::{CStr, CString},
+    fs,
+    os::unix::{ffi::OsStrExt, fs::{lchown, MetadataExt, PermissionsExt}},
+    path::{Path, PathBuf},
+};
+use nix::{
+    mount::{mount, MsFlags},
+    sched::{unshare, CloneFlags},
+    unistd::{chdir, geteuid, setgid, setgroups, setuid, Gid, Uid},
+};
+
+use crate::error::AppError;
+
+/// Isolated projects run as uid and gid ID_BASE + project id. These don't need
+/// an entry in /etc/passwd, they only have to stay clear of real users.
+const ID_BASE: u32 = 100_000;
+/// Mounts that stay writable in a mount namespace, they hold device nodes and
+/// kernel interfaces rather than files.
+const PSEUDO_MOUNTS: [&str; 3] = ["/proc", "/sys", "/dev"];
+const NONE: Option<&CStr> = None;
+
+/// Who a project's install, build and run commands execute as.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Isolation {
+    /// The node's own user, as before.
+    None,
+    /// A user and group of its own that can only write its working directory.
+    User,
+    /// Its own user, in a mount namespace where everything but the working
+    /// directory and a private /tmp is read-only.
+    Namespace,
+}
+
+impl Isolation {
+    pub fn parse(value: Option<&str>) -> Result<Self, AppError> {
+        match value.map(str::trim) {
+            None | Some("") | Some("none") => Ok(Isolation::None),
+            Some("user") => Ok(Isolation::User),
+            Some("namespace") => Ok(Isolation::Namespace),
+            Some(_) => Err(AppError::BadRequest),
+        }
+    }
+}
+
+/// The identity and filesystem view a project's commands are started with.
+#[derive(Clone)]
+pub struct Sandbox {
+    uid: u32,
+    gid: u32,
+    project_dir: PathBuf,
+    work_dir: PathBuf,
+    namespace: bool,
+}
+
+impl Sandbox {
+    /// None for projects that aren't isolated. `work_dir` is where the
+    /// commands run and the only place they can write to.
+    pub fn new(isolation: Isolation, project_id: i32, project_dir: &str, work_dir: &str) -> Result<Option<Self>, AppError> {
+        if isolation == Isolation::None {
+            return Ok(None);
+        }
+        if !geteuid().is_root() {
+            return Err(AppError::Internal("Isolated projects need the node to run as root".to_string()));
+        }
+        let id = ID_BASE + project_id as u32;
+        Ok(Some(Sandbox {
+            uid: id,
+            gid: id,
+            project_dir: fs::canonicalize(project_dir)?,
+            work_dir: fs::canonicalize(work_dir)?,
+            namespace: isolation == Isolation::Namespace,
+        }))
+    }
+
+    /// Hands the working directory to the project's user and closes the
+    /// project directory to everyone else.
+    pub fn prepare(&self) -> std::io::Result<()> {
+        lchown(&self.project_dir, Some(0), Some(self.gid))?;
+        fs::set_permissions(&self.project_dir, fs::Permissions::from_mode(0o750))?;
+        // A build reused by a later deployment is already theirs
+        if fs::metadata(&self.work_dir)?.uid() != self.uid {
+            chown_tree(&self.work_dir, self.uid, self.gid)?;
+        }
+        Ok(())
+    }
+
+    /// Has `command` drop to the project's user, and set up the mount
+    /// namespace first where asked for, right before it is executed.
+    pub fn apply(&self, command: &mut tokio::process::Command) -> std::io::Result<()> {
+        command
+            .env_clear()
+            .env("PATH", std::env::var("PATH").unwrap_or_else(|_| "/usr/local/bin:/usr/bin:/bin".to_string()))
+            .env("HOME", &self.work_dir);
+        if let Ok(lang) = std::env::var("LANG") {
+            command.env("LANG", lang);
+        }
+
+        // Everything the child needs is allocated here, it may not allocate after the fork
+        let work_dir = c_path(&self.work_dir)?;
+        let mounts = if self.namespace { read_only_mounts()? } else { Vec::new() };
+        let (uid, gid, namespace) = (Uid::from_raw(self.uid), Gid::from_raw(self.gid), self.namespace);
+        // A fresh /tmp would hide a working directory that lives inside it
+        let private_tmp = !self.work_dir.starts_with("/tmp");
+        let root = c"/";
+        let tmp = c"/tmp";
+        let tmpfs = c"tmpfs";
+        let tmp_options = c"mode=1777";
+
+        let setup = move || -> std::io::Result<()> {
+            if namespace {
+                unshare(CloneFlags::CLONE_NEWNS)?;
+                // Keep all of this from propagating back to the node's mounts
+                mount(NONE, root, NONE, MsFlags::MS_REC | MsFlags::MS_PRIVATE, NONE)?;
+                // Bound before the rest turns read-only, a bind mount takes over its source's flags
+                mount(Some(work_dir.as_c_str()), work_dir.as_c_str(), NONE, MsFlags::MS_BIND | MsFlags::MS_REC, NONE)?;
+                for target in &mounts {
+                    let flags = MsFlags::MS_REMOUNT | MsFlags::MS_BIND | MsFlags::MS_RDONLY;
+                    // Some mounts refuse, they are left as they are
+                    let _ = mount(NONE, target.as_c_str(), NONE, flags, NONE);
+                }
+                if private_tmp {
+                    mount(Some(tmpfs), tmp, Some(tmpfs), MsFlags::MS_NOSUID | MsFlags::MS_NODEV, Some(tmp_options))?;
+                }
+                // The current directory was entered before the bind mount and still points below it
+                chdir(work_dir.as_c_str())?;
+            }
+            setgroups(&[])?;
+            setgid(gid)?;
+            setuid(uid)?;
+            Ok(())
+        };
+        // SAFETY: the closure only makes system calls on memory prepared before the fork
+        unsafe {
+            command.pre_exec(setup);
+        }
+        Ok(())
+    }
+}
+
+fn c_path(path: &Path) -> std::io::Result<CString> {
+    CString::new(path.as_os_str().as_bytes()).map_err(std::io::Error::other)
+}
+
+/// Every mount point of the node's mount namespace except the pseudo filesystems.
+fn read_only_mounts() -> std::io::Result<Vec<CString>> {
+    let mountinfo = fs::read_to_string("/proc/self/mountinfo")?;
+    let mut mounts = Vec::new();
+    for line in mountinfo.lines() {
+        // The mount point is the fifth field, with spaces and such octal escaped
+        let Some(target) = line.split(' ').nth(4) else { continue };
+        let target = unescape_mountinfo(target);
+        let pseudo = PSEUDO_MOUNTS
+            .iter()
+            .any(|p| target == *p || target.starts_with(&format!("{}/", p)));
+        if !pseudo {
+            mounts.push(CString::new(target).map_err(std::io::Error::other)?);
+        }
+    }
+    Ok(mounts)
+}
+
+fn unescape_mountinfo(field: &str) -> String {
+    let mut out = String::new();
+    let mut chars = field.chars();
+    while let Some(c) = chars.next() {
+        if c == '\\' {
+            let code: String = chars.by_ref().take(3).collect();
+            if let Ok(byte) = u8::from_str_radix(&code, 8) {
+                out.push(byte as char);
+                continue;
+            }
+            out.push(c);
+            out.push_str(&code);
+        } else {
+            out.push(c);
+        }
+    }
+    out
+}
+
+/// chown -R, without following symlinks.
+fn chown_tree(path: &Path, uid: u32, gid: u32) -> std::io::Result<()> {
+    lchown(path, Some(uid), Some(gid))?;
+    if fs::symlink_metadata(path)?.is_dir() {
+        for entry in fs::read_dir(path)? {
+            chown_tree(&entry?.path(), uid, gid)?;
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,151 @@
+use chacha20poly1305::{
+    aead::{Aead, KeyInit, Payload},
+    ChaCha20Poly1305, Key, Nonce,
+};
+use serde_derive::{Deserialize, Serialize};
+use std::{fs, io::Write, os::unix::fs::OpenOptionsExt, sync::OnceLock};
+
+use crate::env_vars;
+use crate::error::AppError;
+
+/// Kept next to data.db. Without it the secrets in there can't be read, so it
+/// has to be backed up along with it.
+const KEY_FILE: &str = "./secret_key";
+const NONCE_LEN: usize = 12;
+/// What reads return in place of a secret's value.
+pub const MASK: &str = "********";
+static CIPHER: OnceLock<ChaCha20Poly1305> = OnceLock::new();
+
+/// A project environment variable that is stored encrypted and never read
+/// back through the API.
+#[derive(Serialize, Deserialize)]
+pub struct Secret {
+    pub key: Option<String>,
+    /// Always `MASK` in responses.
+    pub value: String,
+    pub updated_at: Option<String>,
+}
+
+/// Loads the node's secret key, generating it on first start.
+pub fn init() {
+    let key = match fs::read_to_string(KEY_FILE).ok().and_then(|key| hex::decode(key.trim()).ok()) {
+        Some(key) if key.len() == 32 => key,
+        Some(_) => panic!("{} does not hold a 32 byte hex key", KEY_FILE),
+        None => {
+            let key = rand::random::<[u8; 32]>().to_vec();
+            let mut file = fs::OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .mode(0o600)
+                .open(KEY_FILE)
+                .unwrap();
+            file.write_all(hex::encode(&key).as_bytes()).unwrap();
+            println!("Generated the key project secrets are encrypted with in {}, back it up along with data.db", KEY_FILE);
+            key
+        }
+    };
+    let _ = CIPHER.set(ChaCha20Poly1305::new(Key::from_slice(&key)));
+}
+
+fn cipher() -> Result<&'static ChaCha20Poly1305, AppError> {
+    CIPHER.get().ok_or_else(|| AppError::Internal("The secret key is not loaded".to_string()))
+}
+
+/// The secret's project and name are bound to its ciphertext, so a value
+/// can't be moved over to another one in the database.
+fn associated_data(project_id: i32, key: &str) -> Vec<u8> {
+    format!("{}/{}", project_id, key).into_bytes()
+}
+
+fn encrypt(project_id: i32, key: &str, value: &str) -> Result<Vec<u8>, AppError> {
+    let nonce = rand::random::<[u8; NONCE_LEN]>();
+    let aad = associated_data(project_id, key);
+    let ciphertext = cipher()?
+        .encrypt(Nonce::from_slice(&nonce), Payload { msg: value.as_bytes(), aad: &aad })
+        .map_err(|_| AppError::Internal(format!("Failed to encrypt secret {}", key)))?;
+    Ok([nonce.as_slice(), &ciphertext].concat())
+}
+
+fn decrypt(project_id: i32, key: &str, sealed: &[u8]) -> Result<String, AppError> {
+    let failed = || AppError::Internal(format!("Failed to decrypt secret {}, was {} replaced?", key, KEY_FILE));
+    if sealed.len() < NONCE_LEN {
+        return Err(failed());
+    }
+    let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+    let aad = associated_data(project_id, key);
+    let value = cipher()?
+        .decrypt(Nonce::from_slice(nonce), Payload { msg: ciphertext, aad: &aad })
+        .map_err(|_| failed())?;
+    String::from_utf8(value).map_err(|_| failed())
+}
+
+async fn project_exists(conn: &libsql::Connection, project_id: i32) -> Result<(), AppError> {
+    let mut rows = conn.query("SELECT id FROM projects WHERE id = ?", [project_id]).await?;
+    rows.next().await?.ok_or(AppError::NotFound)?;
+    Ok(())
+}
+
+/// The project's secrets with their values masked.
+pub async fn list_secrets(conn: &libsql::Connection, project_id: i32) -> Result<Vec<Secret>, AppError> {
+    project_exists(conn, project_id).await?;
+    let mut rows = conn
+        .query("SELECT key, updated_at FROM secrets WHERE project_id = ? ORDER BY key", [project_id])
+        .await?;
+    let mut secrets = Vec::new();
+    while let Some(row) = rows.next().await? {
+        secrets.push(Secret {
+            key: row.get(0)?,
+            value: MASK.to_string(),
+            updated_at: row.get(1)?,
+        });
+    }
+    Ok(secrets)
+}
+
+/// Creates or replaces a secret.
+pub async fn set_secret(conn: &libsql::Connection, project_id: i32, key: &str, value: &str) -> Result<Secret, AppError> {
+    if !env_vars::valid_key(key) || value.contains('\0') {
+        return Err(AppError::BadRequest);
+    }
+    project_exists(conn, project_id).await?;
+    conn.execute(
+        "INSERT INTO secrets (project_id, key, value) VALUES (?, ?, ?)
+         ON CONFLICT(project_id, key) DO UPDATE SET value = excluded.value, updated_at = CURRENT_TIMESTAMP",
+        (project_id, key, encrypt(project_id, key, value)?),
+    )
+    .await?;
+    let mut rows = conn
+        .query("SELECT updated_at FROM secrets WHERE project_id = ? AND key = ?", (project_id, key))
+        .await?;
+    let row = rows.next().await?.ok_or(AppError::NotFound)?;
+    Ok(Secret {
+        key: Some(key.to_string()),
+        value: MASK.to_string(),
+        updated_at: row.get(0)?,
+    })
+}
+
+pub async fn delete_secret(conn: &libsql::Connection, project_id: i32, key: &str) -> Result<(), AppError> {
+    if conn
+        .execute("DELETE FROM secrets WHERE project_id = ? AND key = ?", (project_id, key))
+        .await?
+        == 0
+    {
+        return Err(AppError::NotFound);
+    }
+    Ok(())
+}
+
+/// The project's secrets in the clear, for the environment of its commands.
+pub async fn load(conn: &libsql::Connection, project_id: i32) -> Result<Vec<(String, String)>, AppError> {
+    let mut rows = conn
+        .query("SELECT key, value FROM secrets WHERE project_id = ? ORDER BY key", [project_id])
+        .await?;
+    let mut secrets = Vec::new();
+    while let Some(row) = rows.next().await? {
+        let key: String = row.get(0)?;
+        let value = decrypt(project_id, &key, &row.get::<Vec<u8>>(1)?)?;
+        secrets.push((key, value));
+    }
+    Ok(secrets)
+}
@@ -0,0 +1,432 @@
+use std::{
+    ffi::OsString,
+    fs,
+    io::Write,
+    os::unix::{fs::OpenOptionsExt, process::CommandExt},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use ed25519_dalek::{Signature, VerifyingKey};
+use nix::{errno::Errno, sys::signal::{kill, Signal}, unistd::Pid};
+use serde_derive::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::error::AppError;
+
+/// Where releases are downloaded from when --update-url isn't given, if the
+/// build was pinned to one. No release source is published upstream, so by
+/// default updates are off until a URL and a key are configured.
+const PINNED_URL: Option<&str> = option_env!("EDGEZONE_UPDATE_URL");
+/// The release signing key, as hex, that a build is pinned to when it is compiled.
+const PINNED_KEY: Option<&str> = option_env!("EDGEZONE_UPDATE_KEY");
+const STATUS_FILE: &str = "./update.json";
+/// How long a freshly installed binary has to answer with its new version
+/// before the previous one is put back.
+const HEALTH_TIMEOUT: Duration = Duration::from_secs(60);
+const HEALTH_INTERVAL: Duration = Duration::from_secs(1);
+const STOP_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UpdateState {
+    Idle,
+    Downloading,
+    Verifying,
+    Installing,
+    Restarting,
+    Complete,
+    UpToDate,
+    Failed,
+    RolledBack,
+}
+
+/// Progress of the last update, kept in update.json so that it survives the
+/// restart into the new binary.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct UpdateStatus {
+    pub state: UpdateState,
+    /// The version this node is running right now.
+    #[serde(default)]
+    pub version: String,
+    pub target: Option<String>,
+    pub previous: Option<String>,
+    pub message: Option<String>,
+    pub updated_at: Option<String>,
+}
+
+impl UpdateStatus {
+    /// The status as it was last saved, or idle if no update ever ran.
+    pub fn load() -> Self {
+        let mut status = fs::read_to_string(STATUS_FILE)
+            .ok()
+            .and_then(|json| serde_json::from_str::<UpdateStatus>(&json).ok())
+            .unwrap_or(UpdateStatus {
+                state: UpdateState::Idle,
+                version: String::new(),
+                target: None,
+                previous: None,
+                message: None,
+                updated_at: None,
+            });
+        status.version = env!("CARGO_PKG_VERSION").to_string();
+        status
+    }
+
+    fn save(&mut self, state: UpdateState, message: Option<String>) {
+        self.state = state;
+        self.message = message;
+        self.updated_at = Some(chrono::Utc::now().format("%Y-%m-%d %H:%M:%S").to_string());
+        let Ok(json) = serde_json::to_string(self) else { return };
+        // Written next to the old file and renamed over it, so a reader never sees half of it
+        let tmp = format!("{}.tmp", STATUS_FILE);
+        if fs::write(&tmp, json).and_then(|_| fs::rename(&tmp, STATUS_FILE)).is_err() {
+            eprintln!("Failed to save update status");
+        }
+    }
+}
+
+/// Downloads, verifies and installs new releases of the node. Only one
+/// update runs at a time.
+#[derive(Clone)]
+pub struct Updater {
+    source: Option<Arc<str>>,
+    key: Option<Arc<str>>,
+    running: Arc<AtomicBool>,
+}
+
+impl Updater {
+    /// `source` is a base URL or a local directory holding the release files,
+    /// both override what was pinned at build time.
+    pub fn new(source: Option<String>, key: Option<String>) -> Self {
+        let source = source.filter(|s| !s.trim().is_empty()).or(PINNED_URL.map(String::from));
+        let key = key.filter(|k| !k.trim().is_empty()).or(PINNED_KEY.map(String::from));
+        if source.is_none() || key.is_none() {
+            println!("Updates are off, they need both --update-url and --update-key");
+        }
+        Updater {
+            source: source.map(|s| Arc::from(s.trim().trim_end_matches('/'))),
+            key: key.map(|k| Arc::from(k.trim())),
+            running: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// Starts an update in the background. Without `force` only a newer
+    /// version than the running one is installed.
+    pub fn start(&self, force: bool) -> Result<UpdateStatus, AppError> {
+        if self.running.swap(true, Ordering::SeqCst) {
+            return Err(AppError::BadRequest);
+        }
+        let mut status = UpdateStatus::load();
+        status.target = None;
+        status.previous = None;
+        status.save(UpdateState::Downloading, None);
+
+        let updater = self.clone();
+        let mut progress = status.clone();
+        tokio::spawn(async move {
+            if let Err(e) = updater.run(&mut progress, force).await {
+                let message = match e {
+                    AppError::Internal(message) => message,
+                    e => format!("{:?}", e),
+                };
+                eprintln!("Update failed: {}", message);
+                progress.save(UpdateState::Failed, Some(message));
+            }
+            updater.running.store(false, Ordering::SeqCst);
+        });
+        Ok(status)
+    }
+
+    async fn run(&self, status: &mut UpdateStatus, force: bool) -> Result<(), AppError> {
+        let source = self.source.as_deref().ok_or_else(|| internal("No update source is configured, set --update-url"))?;
+        let key = self.key.as_deref().ok_or_else(|| internal("No update signing key is configured, set --update-key"))?;
+        let name = format!("edgezone-node-{}", std::env::consts::ARCH);
+        println!("Downloading {} from {}", name, source);
+        let binary = fetch(source, &name).await?;
+        let checksum = fetch(source, &format!("{}.sha256", name)).await?;
+        let signature = fetch(source, &format!("{}.sig", name)).await?;
+
+        status.save(UpdateState::Verifying, None);
+        verify(&binary, &checksum, &signature, key)?;
+
+        let exe = std::env::current_exe()?;
+        let new = with_suffix(&exe, "new");
+        let old = with_suffix(&exe, "old");
+        let mut file = fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o755)
+            .open(&new)?;
+        file.write_all(&binary)?;
+        file.sync_all()?;
+        drop(file);
+
+        // Running it also proves that it was built for this machine
+        let target = match binary_version(&new).await {
+            Ok(target) => target,
+            Err(e) => {
+                let _ = fs::remove_file(&new);
+                return Err(e);
+            }
+        };
+        let current = env!("CARGO_PKG_VERSION");
+        status.target = Some(target.clone());
+        status.previous = Some(current.to_string());
+        if !force && parse_version(&target) <= parse_version(current) {
+            let _ = fs::remove_file(&new);
+            status.save(UpdateState::UpToDate, Some(format!("{} is not newer than {}", target, current)));
+            return Ok(());
+        }
+
+        status.save(UpdateState::Installing, None);
+        fs::copy(&exe, &old)?;
+        fs::rename(&new, &exe)?;
+
+        status.save(UpdateState::Restarting, None);
+        println!("Installed {}, restarting...", target);
+        // The previous binary keeps an eye on the new one and puts itself back if it has to. It is
+        // started through a shell that exits right away, so that it isn't left a zombie of the new node
+        let args: Vec<OsString> = std::env::args_os().skip(1).collect();
+        tokio::process::Command::new("sh")
+            .arg("-c")
+            .arg("\"$@\" &")
+            .arg("sh")
+            .arg(&old)
+            .args(watchdog_args(std::process::id(), &args))
+            .process_group(0)
+            .status()
+            .await?;
+
+        let error = std::process::Command::new(&exe).args(&args).exec();
+        // exec only returns when it failed, in which case this node simply carries on. The
+        // watchdog leaves once it sees the update is no longer restarting.
+        fs::rename(&old, &exe)?;
+        Err(internal(&format!("Failed to start {}: {}", target, error)))
+    }
+}
+
+async fn fetch(source: &str, name: &str) -> Result<Vec<u8>, AppError> {
+    let location = format!("{}/{}", source, name);
+    if location.starts_with("http://") || location.starts_with("https://") {
+        let response = reqwest::get(&location)
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(|e| internal(&format!("Failed to download {}: {}", location, e)))?;
+        let bytes = response
+            .bytes()
+            .await
+            .map_err(|e| internal(&format!("Failed to download {}: {}", location, e)))?;
+        Ok(bytes.to_vec())
+    } else {
+        let path = location.strip_prefix("file://").unwrap_or(&location);
+        fs::read(path).map_err(|e| internal(&format!("Failed to read {}: {}", path, e)))
+    }
+}
+
+fn internal(message: &str) -> AppError {
+    AppError::Internal(message.to_string())
+}
+
+/// Checks a release binary against its SHA-256 checksum file, in the format
+/// `sha256sum` writes, and its ed25519 signature, raw or as hex.
+fn verify(binary: &[u8], checksum: &[u8], signature: &[u8], key: &str) -> Result<(), AppError> {
+    let expected = String::from_utf8_lossy(checksum)
+        .split_whitespace()
+        .next()
+        .unwrap_or_default()
+        .to_lowercase();
+    if hex::encode(Sha256::digest(binary)) != expected {
+        return Err(internal("Checksum mismatch"));
+    }
+
+    let key: [u8; 32] = hex::decode(key)
+        .ok()
+        .and_then(|key| key.try_into().ok())
+        .ok_or_else(|| internal("The update signing key is not a 32 byte hex string"))?;
+    let key = VerifyingKey::from_bytes(&key).map_err(|_| internal("The update signing key is invalid"))?;
+    let signature: [u8; 64] = match signature.len() {
+        64 => signature.try_into().ok(),
+        _ => hex::decode(String::from_utf8_lossy(signature).trim()).ok().and_then(|s| s.try_into().ok()),
+    }
+    .ok_or_else(|| internal("Malformed signature"))?;
+    key.verify_strict(binary, &Signature::from_bytes(&signature))
+        .map_err(|_| internal("Signature verification failed"))
+}
+
+/// Asks a binary for its version, `--version` prints `edgezone-node 0.4.0`.
+async fn binary_version(path: &Path) -> Result<String, AppError> {
+    let output = tokio::process::Command::new(path)
+        .arg("--version")
+        .output()
+        .await
+        .map_err(|e| internal(&format!("The new binary does not run here: {}", e)))?;
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    match stdout.split_whitespace().last() {
+        Some(version) if output.status.success() => Ok(version.to_string()),
+        _ => Err(internal("The new binary did not report a version")),
+    }
+}
+
+fn parse_version(version: &str) -> Vec<u64> {
+    version
+        .trim_start_matches('v')
+        .split(['.', '-', '+'])
+        .map_while(|part| part.parse().ok())
+        .collect()
+}
+
+/// The arguments the watchdog is started with: the node's own, after the
+/// pid it watches.
+fn watchdog_args(pid: u32, node_args: &[OsString]) -> Vec<OsString> {
+    let mut args = vec![OsString::from("--watch-update"), OsString::from(pid.to_string())];
+    args.extend_from_slice(node_args);
+    args
+}
+
+/// The node's arguments from the watchdog's command line, wherever
+/// `--watch-update` ended up in it.
+fn node_args(watchdog_args: impl IntoIterator<Item = OsString>) -> Vec<OsString> {
+    let mut args = watchdog_args.into_iter().skip(1);
+    let mut node_args = Vec::new();
+    while let Some(arg) = args.next() {
+        if arg == "--watch-update" {
+            args.next();
+        } else if !arg.to_string_lossy().starts_with("--watch-update=") {
+            node_args.push(arg);
+        }
+    }
+    node_args
+}
+
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut path = path.as_os_str().to_owned();
+    path.push(".");
+    path.push(suffix);
+    PathBuf::from(path)
+}
+
+/// Run by the previous binary right after an update. Waits for the node
+/// (`pid`) to answer on `port` with the version it was updated to, and if it
+/// doesn't in time, puts the previous binary back and runs that instead.
+pub async fn watch(pid: i32, port: u16, token: &str) {
+    let Ok(old) = std::env::current_exe() else { return };
+    let exe = old.with_extension("");
+    let mut status = UpdateStatus::load();
+    let target = status.target.clone().unwrap_or_default();
+    let pid = Pid::from_raw(pid);
+
+    let client = reqwest::Client::new();
+    let url = format!("http://127.0.0.1:{}/info", port);
+    let started = tokio::time::Instant::now();
+    while started.elapsed() < HEALTH_TIMEOUT {
+        tokio::time::sleep(HEALTH_INTERVAL).await;
+        if UpdateStatus::load().state != UpdateState::Restarting {
+            return;
+        }
+        if kill(pid, None) == Err(Errno::ESRCH) {
+            break;
+        }
+        let response = client.get(&url).bearer_auth(token).timeout(HEALTH_INTERVAL).send().await;
+        let Ok(response) = response else { continue };
+        let Ok(body) = response.bytes().await else { continue };
+        let Ok(info) = serde_json::from_slice::<serde_json::Value>(&body) else { continue };
+        if info["version"].as_str() == Some(target.as_str()) {
+            let message = format!("Updated from {} to {}", status.previous.clone().unwrap_or_default(), target);
+            status.save(UpdateState::Complete, Some(message));
+            return;
+        }
+    }
+
+    eprintln!("Version {} did not come up, rolling back", target);
+    if let Err(e) = fs::rename(&old, &exe) {
+        eprintln!("Failed to restore the previous binary: {}", e);
+        return;
+    }
+    status.save(UpdateState::RolledBack, Some(format!("{} did not become healthy within {}s", target, HEALTH_TIMEOUT.as_secs())));
+
+    if kill(pid, Signal::SIGTERM).is_ok() {
+        let stopping = tokio::time::Instant::now();
+        while kill(pid, None).is_ok() && stopping.elapsed() < STOP_TIMEOUT {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        let _ = kill(pid, Signal::SIGKILL);
+    }
+
+    // Take over as the node, with the arguments it was started with
+    let error = std::process::Command::new(&exe).args(node_args(std::env::args_os())).exec();
+    eprintln!("Failed to start the previous binary: {}", error);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ed25519_dalek::{Signer, SigningKey};
+
+    const BINARY: &[u8] = b"\x7fELF a release";
+
+    fn release(signing: &SigningKey) -> (String, Vec<u8>, String) {
+        let checksum = format!("{}  edgezone-node-aarch64\n", hex::encode(Sha256::digest(BINARY)));
+        let signature = signing.sign(BINARY).to_bytes().to_vec();
+        (checksum, signature, hex::encode(signing.verifying_key().to_bytes()))
+    }
+
+    #[test]
+    fn verify_accepts_a_signed_release() {
+        let (checksum, signature, key) = release(&SigningKey::from_bytes(&[7; 32]));
+        assert!(verify(BINARY, checksum.as_bytes(), &signature, &key).is_ok());
+        // Signatures also come as hex, with a trailing newline
+        let hex_signature = format!("{}\n", hex::encode(&signature));
+        assert!(verify(BINARY, checksum.to_uppercase().as_bytes(), hex_signature.as_bytes(), &key).is_ok());
+    }
+
+    #[test]
+    fn verify_rejects_tampering() {
+        let (checksum, signature, key) = release(&SigningKey::from_bytes(&[7; 32]));
+        let (_, _, other_key) = release(&SigningKey::from_bytes(&[8; 32]));
+        let mut tampered = BINARY.to_vec();
+        tampered.push(0);
+        let mut bad_signature = signature.clone();
+        bad_signature[0] ^= 1;
+
+        assert!(verify(&tampered, checksum.as_bytes(), &signature, &key).is_err());
+        assert!(verify(BINARY, b"", &signature, &key).is_err());
+        assert!(verify(BINARY, checksum.as_bytes(), &bad_signature, &key).is_err());
+        assert!(verify(BINARY, checksum.as_bytes(), &signature[..63], &key).is_err());
+        assert!(verify(BINARY, checksum.as_bytes(), &signature, &other_key).is_err());
+        assert!(verify(BINARY, checksum.as_bytes(), &signature, "not hex").is_err());
+        assert!(verify(BINARY, checksum.as_bytes(), &signature, &key[..62]).is_err());
+    }
+
+    #[test]
+    fn parse_version_orders_releases() {
+        assert_eq!(parse_version("0.4.0"), vec![0, 4, 0]);
+        assert_eq!(parse_version("v1.2.3"), vec![1, 2, 3]);
+        assert_eq!(parse_version("1.2.3-rc1"), vec![1, 2, 3]);
+        assert_eq!(parse_version("1.2.3+build.5"), vec![1, 2, 3]);
+        assert_eq!(parse_version("garbage"), Vec::<u64>::new());
+        assert!(parse_version("0.10.0") > parse_version("0.9.9"));
+        assert!(parse_version("1.0") < parse_version("1.0.1"));
+        assert!(parse_version("0.4.0") <= parse_version("0.4.0"));
+    }
+
+    #[test]
+    fn watchdog_passes_the_node_arguments_back() {
+        let args: Vec<OsString> = ["--port", "3900", "--proxy", "--watch-update-url-like", "x"]
+            .into_iter()
+            .map(OsString::from)
+            .collect();
+        let mut watchdog = vec![OsString::from("/usr/bin/edgezone-node.old")];
+        watchdog.extend(watchdog_args(4242, &args));
+        assert_eq!(watchdog[1..3], [OsString::from("--watch-update"), OsString::from("4242")]);
+        assert_eq!(node_args(watchdog), args);
+
+        let moved = ["edgezone-node.old", "--port", "3900", "--watch-update=4242", "--proxy"].map(OsString::from);
+        assert_eq!(node_args(moved), ["--port", "3900", "--proxy"].map(OsString::from));
+        assert!(node_args([OsString::from("edgezone-node.old")]).is_empty());
+    }
+}